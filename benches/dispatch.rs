@@ -0,0 +1,199 @@
+// Match dispatch over Instruction against a Box<dyn Expr> per instruction
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use interest::bytecode::ByteCode;
+use interest::expr::Expr;
+use interest::instruction::Instruction;
+use interest::interpreter::{
+    AddrType, ControlFlow, SlotType, Stack, StackItem, Trap, ValueType, VariableSlots, VariableTable
+};
+
+// Stays off the stack after the first iteration, so that the loop times the
+// dispatch and not the stack growing
+const DISPATCH_SRC: &str = "
+    START
+    LOAD_VAL 1000000
+    LOOP
+    LABEL 'a'
+    LABEL 'b'
+    LABEL 'c'
+    ENDLOOP
+";
+
+// The instructions of DISPATCH_SRC the way they were before Instruction, one
+// struct each behind a Box<dyn Expr>. A snapshot of the Expr impls of that
+// time, they do not follow changes to the interpreter
+struct Start {}
+impl Expr for Start {
+    fn name(&self) -> &'static str {
+        "Start"
+    }
+
+    fn eval(&self,
+        _thread_global: &mut VariableTable<ValueType>,
+        _stack: &mut Stack<StackItem>,
+        _var_table: &mut VariableSlots<ValueType>,
+        _func_table: &VariableTable<AddrType>,
+        _control_flow: &mut ControlFlow
+    ) -> Result<(), Trap> {
+        Ok(())
+    }
+}
+
+struct Label {}
+impl Expr for Label {
+    fn name(&self) -> &'static str {
+        "Label"
+    }
+
+    fn eval(&self,
+        _thread_global: &mut VariableTable<ValueType>,
+        _stack: &mut Stack<StackItem>,
+        _var_table: &mut VariableSlots<ValueType>,
+        _func_table: &VariableTable<AddrType>,
+        _control_flow: &mut ControlFlow
+    ) -> Result<(), Trap> {
+        Ok(())
+    }
+}
+
+struct LoadVal {
+    literal: ValueType
+}
+impl Expr for LoadVal {
+    fn name(&self) -> &'static str {
+        "LoadVal"
+    }
+
+    fn eval(&self,
+        _thread_global: &mut VariableTable<ValueType>,
+        stack: &mut Stack<StackItem>,
+        _var_table: &mut VariableSlots<ValueType>,
+        _func_table: &VariableTable<AddrType>,
+        _control_flow: &mut ControlFlow
+    ) -> Result<(), Trap> {
+        stack.push(StackItem::Value(self.literal));
+        Ok(())
+    }
+}
+
+struct Loop {
+    loop_var: Option<SlotType>,
+    count_var: Option<SlotType>,
+    endloop: Option<AddrType>
+}
+impl Expr for Loop {
+    fn name(&self) -> &'static str {
+        "Loop"
+    }
+
+    fn eval(&self,
+        _thread_global: &mut VariableTable<ValueType>,
+        stack: &mut Stack<StackItem>,
+        var_table: &mut VariableSlots<ValueType>,
+        _func_table: &VariableTable<AddrType>,
+        control_flow: &mut ControlFlow
+    ) -> Result<(), Trap> {
+        let loop_var = self.loop_var.unwrap();
+        let count_var = self.count_var.unwrap();
+        match var_table.read(loop_var) {
+            Some(var) => {
+                if var >= var_table.read(count_var).unwrap() {
+                    var_table.remove(loop_var);
+                    var_table.remove(count_var);
+                    *control_flow = ControlFlow::JumpTo(self.endloop.unwrap() + 1);
+                }
+            },
+            None => {
+                let count = stack.pop_value()?;
+                var_table.write(count_var, count);
+                var_table.write(loop_var, 0);
+            }
+        };
+        Ok(())
+    }
+}
+
+struct EndLoop {
+    loop_var: Option<SlotType>,
+    loopstart: Option<AddrType>,
+}
+impl Expr for EndLoop {
+    fn name(&self) -> &'static str {
+        "EndLoop"
+    }
+
+    fn eval(&self,
+        _thread_global: &mut VariableTable<ValueType>,
+        _stack: &mut Stack<StackItem>,
+        var_table: &mut VariableSlots<ValueType>,
+        _func_table: &VariableTable<AddrType>,
+        control_flow: &mut ControlFlow
+    ) -> Result<(), Trap> {
+        let loop_var_val = var_table.read(self.loop_var.unwrap()).unwrap() + 1;
+        var_table.write(self.loop_var.unwrap(), loop_var_val);
+        *control_flow = ControlFlow::JumpTo(self.loopstart.unwrap());
+        Ok(())
+    }
+}
+
+fn boxed(instruction: &Instruction) -> Box<dyn Expr> {
+    match instruction {
+        Instruction::Start => Box::new(Start {}),
+        Instruction::Label => Box::new(Label {}),
+        Instruction::LoadVal(literal) => Box::new(LoadVal { literal: *literal }),
+        Instruction::Loop { loop_var, count_var, endloop } => Box::new(Loop {
+            loop_var: Some(*loop_var),
+            count_var: Some(*count_var),
+            endloop: Some(*endloop),
+        }),
+        Instruction::EndLoop { loop_var, loopstart } => Box::new(EndLoop {
+            loop_var: Some(*loop_var),
+            loopstart: Some(*loopstart),
+        }),
+        _ => unreachable!("{} is not in DISPATCH_SRC", instruction.name()),
+    }
+}
+
+fn run(len: usize, eval: impl Fn(AddrType, &mut Stack<StackItem>, &mut VariableSlots<ValueType>, &mut ControlFlow)) {
+    let mut stack = Stack::new();
+    let mut var_table = VariableSlots::default();
+    let mut addr = 0;
+    while addr < len {
+        let mut control_flow = ControlFlow::Normal;
+        eval(addr, &mut stack, &mut var_table, &mut control_flow);
+        match control_flow {
+            ControlFlow::JumpTo(line) => addr = line,
+            _ => addr += 1,
+        }
+    }
+}
+
+fn time(f: impl Fn()) -> Duration {
+    (0..5).map(|_| {
+        let start = Instant::now();
+        f();
+        start.elapsed()
+    }).min().unwrap()
+}
+
+fn main() {
+    let byte_code = ByteCode::from_source(DISPATCH_SRC);
+    let len = byte_code.end_addr();
+    let code: Vec<&Instruction> = (0..len).map(|addr| byte_code.get_line(addr).unwrap()).collect();
+    let boxed: Vec<Box<dyn Expr>> = code.iter().map(|instruction| boxed(instruction)).collect();
+    let mut globals = VariableTable::default();
+    let globals = std::cell::RefCell::new(&mut globals);
+    let func_table = VariableTable::default();
+
+    let matched = time(|| run(len, |addr, stack, var_table, control_flow| {
+        black_box(code[addr]).eval(&mut globals.borrow_mut(), stack, var_table, &func_table, control_flow).unwrap();
+    }));
+    let virtual_call = time(|| run(len, |addr, stack, var_table, control_flow| {
+        black_box(&boxed[addr]).eval(&mut globals.borrow_mut(), stack, var_table, &func_table, control_flow).unwrap();
+    }));
+    println!("match:        {:?}", matched);
+    println!("virtual call: {:?}", virtual_call);
+    println!("speedup: {:.2}x", virtual_call.as_secs_f64() / matched.as_secs_f64());
+}
@@ -0,0 +1,50 @@
+// Variable access of a loop-heavy script, by name against by slot
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use interest::bytecode::ByteCode;
+use interest::interpreter::{Interpreter, RunOutcome, StackItem, ThreadExit, ValueType};
+
+const ITERATIONS: ValueType = 1_000_000;
+
+// The variables the loop below reads and writes on every iteration
+const LOOP_SRC: &str = "
+    START
+    LOAD_VAL 0
+    WRITE_VAR 'sum'
+    LOAD_VAL 1000000
+    LOOP
+    READ_VAR 'sum'
+    LOAD_VAL 3
+    ADD
+    WRITE_VAR 'sum'
+    ENDLOOP
+    READ_VAR 'sum'
+";
+
+// Thread variables used to live in a VariableTable looked up by name, the
+// table globals still use
+fn by_name() -> String {
+    LOOP_SRC.replace("_VAR 'sum'", "_GLOBAL 'sum'")
+}
+
+fn time(source: &str) -> Duration {
+    (0..5).map(|_| {
+        let mut interpreter = Interpreter::new(ByteCode::from_source(source));
+        interpreter.set_step_limit(usize::MAX);
+        let start = Instant::now();
+        assert_eq!(black_box(interpreter.run()), RunOutcome::Finished);
+        let elapsed = start.elapsed();
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(3 * ITERATIONS)))));
+        elapsed
+    }).min().unwrap()
+}
+
+fn main() {
+    let named = time(&by_name());
+    let slotted = time(LOOP_SRC);
+    println!("by name: {:?}", named);
+    println!("by slot: {:?}", slotted);
+    println!("speedup: {:.1}x", named.as_secs_f64() / slotted.as_secs_f64());
+}
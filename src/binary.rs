@@ -0,0 +1,518 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::bytecode::{ByteCode, SourcePos};
+use crate::instruction::Instruction;
+use crate::interpreter::{AddrType, SlotType, ValueType, VariableTable};
+use crate::verify;
+
+
+// Layout of a .brc file, integers are little endian:
+//   magic "BRC\0", version u16, payload length u32, checksum u32
+//   payload: start address, constant pool, label table, variable slot count,
+//   debug info and the instruction stream, each table prefixed by its length
+//   debug info: variable names, the file name if there is one and the line
+//   and column of every instruction
+const MAGIC: &[u8; 4] = b"BRC\0";
+pub const VERSION: u16 = 3;
+const HEADER_LEN: usize = 14;
+
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    BadMagic,
+    Version(u16),
+    Truncated,
+    Checksum,
+    BadOpcode(u8),
+    BadConstant(u32),
+    // Host instructions only exist in the process that registered them
+    HostInstruction(&'static str),
+    // Decoded fine but would not run, like a jump past the end
+    Invalid(String),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Io(err) => write!(f, "{}", err),
+            FormatError::BadMagic => write!(f, "not a compiled byte code file"),
+            FormatError::Version(version) =>
+                write!(f, "byte code version {} is not supported, expected {}", version, VERSION),
+            FormatError::Truncated => write!(f, "byte code file is truncated"),
+            FormatError::Checksum => write!(f, "byte code checksum does not match"),
+            FormatError::BadOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+            FormatError::BadConstant(index) => write!(f, "constant {} is not in the pool", index),
+            FormatError::HostInstruction(name) =>
+                write!(f, "host instruction {} can not be saved", name),
+            FormatError::Invalid(message) => write!(f, "invalid byte code: {}", message),
+        }
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(err: io::Error) -> Self {
+        FormatError::Io(err)
+    }
+}
+
+impl ByteCode {
+    // Saves the loaded byte code, debug_info keeps the variable names and
+    // source positions
+    pub fn write_to(&self, writer: &mut impl Write, debug_info: bool) -> Result<(), FormatError> {
+        let mut code = Encoder::default();
+        code.u32(self.exprs.len() as u32);
+        for expr in &self.exprs {
+            code.instruction(expr)?;
+        }
+
+        let mut labels: Vec<(&str, AddrType)> = self.labels.iter().collect();
+        labels.sort();
+        let labels: Vec<(u32, AddrType)> = labels.into_iter()
+                .map(|(label, addr)| (code.constant(label), addr))
+                .collect();
+        let variables: Vec<u32> = match debug_info {
+            true => self.variables.iter().map(|name| code.constant(name)).collect(),
+            false => Vec::new(),
+        };
+        let file = match debug_info {
+            true => self.file.as_ref().map(|file| code.constant(file)),
+            false => None,
+        };
+
+        let mut payload = Encoder::default();
+        payload.u32(self.start_addr as u32);
+        payload.u32(code.pool.len() as u32);
+        for constant in &code.pool {
+            payload.u32(constant.len() as u32);
+            payload.bytes.extend_from_slice(constant.as_bytes());
+        }
+        payload.u32(labels.len() as u32);
+        for (label, addr) in labels {
+            payload.u32(label);
+            payload.u32(addr as u32);
+        }
+        let slot_count = self.exprs.iter().flat_map(slots).map(|slot| slot + 1)
+                .fold(self.variables.len(), usize::max);
+        payload.u32(slot_count as u32);
+        payload.u8(debug_info as u8);
+        if debug_info {
+            payload.u32(variables.len() as u32);
+            for variable in variables {
+                payload.u32(variable);
+            }
+            payload.u8(file.is_some() as u8);
+            if let Some(file) = file {
+                payload.u32(file);
+            }
+            payload.u32(self.positions.len() as u32);
+            for pos in &self.positions {
+                payload.u32(pos.line as u32);
+                payload.u32(pos.column as u32);
+            }
+        }
+        payload.bytes.extend_from_slice(&code.bytes);
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(payload.bytes.len() as u32).to_le_bytes())?;
+        writer.write_all(&checksum(&payload.bytes).to_le_bytes())?;
+        writer.write_all(&payload.bytes)?;
+        Ok(())
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, FormatError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        let mut header = Decoder { bytes: &bytes, pool: Vec::new() };
+        if header.take(MAGIC.len())? != MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+        let version = u16::from_le_bytes(header.take(2)?.try_into().unwrap());
+        if version != VERSION {
+            return Err(FormatError::Version(version));
+        }
+        let len = header.u32()? as usize;
+        let sum = header.u32()?;
+        let payload = &bytes[HEADER_LEN..];
+        if payload.len() < len {
+            return Err(FormatError::Truncated);
+        }
+        if payload.len() > len {
+            return Err(FormatError::Invalid(format!("{} bytes after the payload", payload.len() - len)));
+        }
+        if checksum(payload) != sum {
+            return Err(FormatError::Checksum);
+        }
+
+        let mut payload = Decoder { bytes: payload, pool: Vec::new() };
+        let start_addr = payload.u32()? as AddrType;
+        for _ in 0..payload.u32()? {
+            let len = payload.u32()? as usize;
+            let constant = String::from_utf8_lossy(payload.take(len)?).into_owned();
+            payload.pool.push(constant);
+        }
+        let mut labels = VariableTable::default();
+        for _ in 0..payload.u32()? {
+            let label = payload.constant()?;
+            labels.write(&label, payload.u32()? as AddrType);
+        }
+        let slot_count = payload.u32()? as usize;
+        let mut variables = Vec::new();
+        let mut file = None;
+        let mut positions = Vec::new();
+        if payload.u8()? != 0 {
+            for _ in 0..payload.u32()? {
+                variables.push(payload.constant()?);
+            }
+            if payload.u8()? != 0 {
+                file = Some(payload.constant()?);
+            }
+            for _ in 0..payload.u32()? {
+                let line = payload.u32()? as usize;
+                positions.push(SourcePos { line, column: payload.u32()? as usize });
+            }
+        }
+        let mut exprs = Vec::new();
+        for _ in 0..payload.u32()? {
+            exprs.push(payload.instruction()?);
+        }
+        if !payload.bytes.is_empty() {
+            return Err(FormatError::Invalid(format!("{} bytes after the instructions", payload.bytes.len())));
+        }
+        validate(&exprs, start_addr, slot_count, &labels, &variables, &positions)
+                .map_err(FormatError::Invalid)?;
+
+        Ok(ByteCode { labels, variables, exprs, start_addr, warnings: Vec::new(), file, positions })
+    }
+}
+
+fn slots(expr: &Instruction) -> Vec<SlotType> {
+    match expr {
+        Instruction::WriteVar(slot) | Instruction::ReadVar(slot) | Instruction::TeeVar(slot) =>
+            vec![*slot],
+        Instruction::Loop { loop_var, count_var, .. } => vec![*loop_var, *count_var],
+        Instruction::EndLoop { loop_var, .. } => vec![*loop_var],
+        _ => Vec::new(),
+    }
+}
+
+fn targets(expr: &Instruction) -> Vec<AddrType> {
+    match expr {
+        Instruction::Call { func_line, return_line } => vec![*func_line, *return_line],
+        Instruction::Jump(addr) | Instruction::JumpZero(addr) | Instruction::LoadAddr(addr) =>
+            vec![*addr],
+        Instruction::Loop { endloop, .. } => vec![*endloop],
+        Instruction::EndLoop { loopstart, .. } => vec![*loopstart],
+        Instruction::While { endwhile } => vec![*endwhile],
+        Instruction::EndWhile { whilestart } => vec![*whilestart],
+        _ => Vec::new(),
+    }
+}
+
+// A file is not checked by the linker, everything the interpreter and the
+// verifier index with has to be in range before the verifier runs
+fn validate(exprs: &[Instruction], start_addr: AddrType, slot_count: usize,
+        labels: &VariableTable<AddrType>, variables: &[String], positions: &[SourcePos])
+        -> Result<(), String> {
+    let len = exprs.len();
+    // Every slot is made by an instruction using it, a loop makes two
+    if slot_count > 2 * len || variables.len() > slot_count {
+        return Err(format!("{} variable slots for {} instructions", slot_count, len));
+    }
+    if !positions.is_empty() && positions.len() != len {
+        return Err(format!("{} source positions for {} instructions", positions.len(), len));
+    }
+    if start_addr > len {
+        return Err(format!("start address {} is past the end", start_addr));
+    }
+    if let Some((label, addr)) = labels.iter().find(|(_, addr)| *addr > len) {
+        return Err(format!("label '{}' at {} is past the end", label, addr));
+    }
+    for (addr, expr) in exprs.iter().enumerate() {
+        if let Some(slot) = slots(expr).into_iter().find(|slot| *slot >= slot_count) {
+            return Err(format!("{} at {} uses slot {} of {}", expr.name(), addr, slot, slot_count));
+        }
+        if let Some(target) = targets(expr).into_iter().find(|target| *target > len) {
+            return Err(format!("{} at {} jumps to {} past the end", expr.name(), addr, target));
+        }
+        let paired = match expr {
+            Instruction::Loop { loop_var, endloop, .. } => matches!(exprs.get(*endloop),
+                Some(Instruction::EndLoop { loop_var: end_var, loopstart })
+                    if end_var == loop_var && *loopstart == addr),
+            Instruction::EndLoop { loop_var, loopstart } => matches!(exprs.get(*loopstart),
+                Some(Instruction::Loop { loop_var: start_var, endloop, .. })
+                    if start_var == loop_var && *endloop == addr),
+            Instruction::While { endwhile } => matches!(exprs.get(*endwhile),
+                Some(Instruction::EndWhile { whilestart }) if *whilestart == addr),
+            Instruction::EndWhile { whilestart } => matches!(exprs.get(*whilestart),
+                Some(Instruction::While { endwhile }) if *endwhile == addr),
+            _ => true,
+        };
+        if !paired {
+            return Err(format!("{} at {} does not match its block", expr.name(), addr));
+        }
+        // Arguments and spawned threads are pushed by instructions
+        if let Instruction::Spawn(count) | Instruction::SpawnN(count) = expr {
+            if *count > len {
+                return Err(format!("{} at {} takes {} items", expr.name(), addr, count));
+            }
+        }
+    }
+    match verify::verify(exprs, start_addr).into_iter().next() {
+        Some((addr, message)) => Err(format!("at {}: {}", addr, message)),
+        None => Ok(()),
+    }
+}
+
+// FNV-1a
+pub(crate) fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193))
+}
+
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+    pool: Vec<String>,
+}
+
+impl Encoder {
+    fn u8(&mut self, val: u8) {
+        self.bytes.push(val);
+    }
+
+    fn u32(&mut self, val: u32) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn value(&mut self, val: ValueType) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn constant(&mut self, val: &str) -> u32 {
+        let index = match self.pool.iter().position(|constant| constant == val) {
+            Some(index) => index,
+            None => {
+                self.pool.push(String::from(val));
+                self.pool.len() - 1
+            },
+        };
+        index as u32
+    }
+
+    fn name(&mut self, opcode: u8, val: &str) {
+        let index = self.constant(val);
+        self.u8(opcode);
+        self.u32(index);
+    }
+
+    fn instruction(&mut self, expr: &Instruction) -> Result<(), FormatError> {
+        match expr {
+            Instruction::Noop => self.u8(0),
+            Instruction::Start => self.u8(1),
+            Instruction::Label => self.u8(2),
+            Instruction::LoadVal(val) => {
+                self.u8(3);
+                self.value(*val);
+            },
+            Instruction::WriteVar(slot) => {
+                self.u8(4);
+                self.u32(*slot as u32);
+            },
+            Instruction::ReadVar(slot) => {
+                self.u8(5);
+                self.u32(*slot as u32);
+            },
+            Instruction::Add => self.u8(6),
+            Instruction::Multiply => self.u8(7),
+            Instruction::Call { func_line, return_line } => {
+                self.u8(8);
+                self.u32(*func_line as u32);
+                self.u32(*return_line as u32);
+            },
+            Instruction::Return => self.u8(9),
+            Instruction::ReturnValue => self.u8(10),
+            Instruction::Jump(line) => {
+                self.u8(11);
+                self.u32(*line as u32);
+            },
+            Instruction::JumpZero(line) => {
+                self.u8(12);
+                self.u32(*line as u32);
+            },
+            Instruction::Loop { loop_var, count_var, endloop } => {
+                self.u8(13);
+                self.u32(*loop_var as u32);
+                self.u32(*count_var as u32);
+                self.u32(*endloop as u32);
+            },
+            Instruction::EndLoop { loop_var, loopstart } => {
+                self.u8(14);
+                self.u32(*loop_var as u32);
+                self.u32(*loopstart as u32);
+            },
+            Instruction::While { endwhile } => {
+                self.u8(15);
+                self.u32(*endwhile as u32);
+            },
+            Instruction::EndWhile { whilestart } => {
+                self.u8(16);
+                self.u32(*whilestart as u32);
+            },
+            Instruction::LoadAddr(addr) => {
+                self.u8(17);
+                self.u32(*addr as u32);
+            },
+            Instruction::LoadChannel(channel) => {
+                self.u8(18);
+                self.value(*channel);
+            },
+            Instruction::SendChannel => self.u8(19),
+            Instruction::RecvChannel => self.u8(20),
+            Instruction::SendTo => self.u8(21),
+            Instruction::Receive => self.u8(22),
+            Instruction::Spawn(argc) => {
+                self.u8(23);
+                self.u32(*argc as u32);
+            },
+            Instruction::SpawnN(count) => {
+                self.u8(24);
+                self.u32(*count as u32);
+            },
+            Instruction::Join => self.u8(25),
+            Instruction::SetPriority => self.u8(26),
+            Instruction::Yield => self.u8(27),
+            Instruction::Sleep(ticks) => {
+                self.u8(28);
+                self.value(*ticks);
+            },
+            Instruction::Now => self.u8(29),
+            Instruction::ThreadId => self.u8(30),
+            Instruction::ThreadCount => self.u8(31),
+            Instruction::SetThreadName(name) => self.name(32, name),
+            Instruction::Kill => self.u8(33),
+            Instruction::Cancel => self.u8(34),
+            Instruction::CheckCancel => self.u8(35),
+            Instruction::Monitor => self.u8(36),
+            Instruction::ReadGlobal(name) => self.name(37, name),
+            Instruction::WriteGlobal(name) => self.name(38, name),
+            Instruction::AtomicAdd(name) => self.name(39, name),
+            Instruction::CompareAndSwap(name) => self.name(40, name),
+            Instruction::FetchAndSet(name) => self.name(41, name),
+            Instruction::Lock(mutex) => self.name(42, mutex),
+            Instruction::Unlock(mutex) => self.name(43, mutex),
+            Instruction::Wait(mutex) => self.name(44, mutex),
+            Instruction::Notify(mutex) => self.name(45, mutex),
+            Instruction::NotifyAll(mutex) => self.name(46, mutex),
+            Instruction::TeeVar(slot) => {
+                self.u8(47);
+                self.u32(*slot as u32);
+            },
+            Instruction::Pop => self.u8(48),
+            Instruction::Subtract => self.u8(49),
+            Instruction::Equal => self.u8(50),
+            Instruction::Host(expr) => return Err(FormatError::HostInstruction(expr.name())),
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pool: Vec<String>,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FormatError> {
+        if self.bytes.len() < len {
+            return Err(FormatError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn addr(&mut self) -> Result<AddrType, FormatError> {
+        Ok(self.u32()? as AddrType)
+    }
+
+    fn value(&mut self) -> Result<ValueType, FormatError> {
+        Ok(ValueType::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn constant(&mut self) -> Result<String, FormatError> {
+        let index = self.u32()?;
+        self.pool.get(index as usize).cloned().ok_or(FormatError::BadConstant(index))
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, FormatError> {
+        let instruction = match self.u8()? {
+            0 => Instruction::Noop,
+            1 => Instruction::Start,
+            2 => Instruction::Label,
+            3 => Instruction::LoadVal(self.value()?),
+            4 => Instruction::WriteVar(self.addr()?),
+            5 => Instruction::ReadVar(self.addr()?),
+            6 => Instruction::Add,
+            7 => Instruction::Multiply,
+            8 => Instruction::Call { func_line: self.addr()?, return_line: self.addr()? },
+            9 => Instruction::Return,
+            10 => Instruction::ReturnValue,
+            11 => Instruction::Jump(self.addr()?),
+            12 => Instruction::JumpZero(self.addr()?),
+            13 => Instruction::Loop {
+                loop_var: self.addr()?,
+                count_var: self.addr()?,
+                endloop: self.addr()?,
+            },
+            14 => Instruction::EndLoop { loop_var: self.addr()?, loopstart: self.addr()? },
+            15 => Instruction::While { endwhile: self.addr()? },
+            16 => Instruction::EndWhile { whilestart: self.addr()? },
+            17 => Instruction::LoadAddr(self.addr()?),
+            18 => Instruction::LoadChannel(self.value()?),
+            19 => Instruction::SendChannel,
+            20 => Instruction::RecvChannel,
+            21 => Instruction::SendTo,
+            22 => Instruction::Receive,
+            23 => Instruction::Spawn(self.addr()?),
+            24 => Instruction::SpawnN(self.addr()?),
+            25 => Instruction::Join,
+            26 => Instruction::SetPriority,
+            27 => Instruction::Yield,
+            28 => Instruction::Sleep(self.value()?),
+            29 => Instruction::Now,
+            30 => Instruction::ThreadId,
+            31 => Instruction::ThreadCount,
+            32 => Instruction::SetThreadName(self.constant()?),
+            33 => Instruction::Kill,
+            34 => Instruction::Cancel,
+            35 => Instruction::CheckCancel,
+            36 => Instruction::Monitor,
+            37 => Instruction::ReadGlobal(self.constant()?),
+            38 => Instruction::WriteGlobal(self.constant()?),
+            39 => Instruction::AtomicAdd(self.constant()?),
+            40 => Instruction::CompareAndSwap(self.constant()?),
+            41 => Instruction::FetchAndSet(self.constant()?),
+            42 => Instruction::Lock(self.constant()?),
+            43 => Instruction::Unlock(self.constant()?),
+            44 => Instruction::Wait(self.constant()?),
+            45 => Instruction::Notify(self.constant()?),
+            46 => Instruction::NotifyAll(self.constant()?),
+            47 => Instruction::TeeVar(self.addr()?),
+            48 => Instruction::Pop,
+            49 => Instruction::Subtract,
+            50 => Instruction::Equal,
+            opcode => return Err(FormatError::BadOpcode(opcode)),
+        };
+        Ok(instruction)
+    }
+}
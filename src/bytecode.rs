@@ -1,18 +1,42 @@
-use std::{fs::File, io::Read};
+use std::{fmt, fs::File, io::Read};
 
-use crate::expr;
-use crate::interpreter::{AddrType, Stack, VariableTable};
+use crate::cfg::Warning;
+use crate::instruction::Instruction;
+use crate::interpreter::{AddrType, SlotType, VariableTable};
 use crate::parse::ParseEngine;
+use crate::verify::{self, Violation};
 
 
+// Where an instruction was written, both counting from 1
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SourcePos {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for SourcePos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Default)]
 pub struct CodeContext {
     line: AddrType,
     start: AddrType,
     labels: VariableTable<AddrType>,
     pub loops: Vec<(AddrType, AddrType)>,
     pub whiles: Vec<(AddrType, AddrType)>,
-    loop_stack: Stack<AddrType>,
-    while_stack: Stack<AddrType>,
+    // Open LOOP and WHILE blocks, innermost last
+    blocks: Vec<(&'static str, AddrType)>,
+    // Found while parsing, by address
+    violations: Vec<(AddrType, String)>,
+    // Lines referring to a label, patched once every label is known
+    references: Vec<(AddrType, String)>,
+    undefined: Vec<String>,
+    // Variables live in the thread, so their names share one set of slots
+    slots: VariableTable<SlotType>,
+    variables: Vec<String>,
 }
 
 impl CodeContext {
@@ -33,48 +57,134 @@ impl CodeContext {
     }
 
     pub fn set_label(&mut self, label: &str, line: AddrType) {
-        self.labels.write_unique(label, line);
+        if self.labels.read(label).is_some() {
+            self.report(format!("label '{}' is defined twice", label));
+            return;
+        }
+        self.labels.write(label, line);
+    }
+
+    // Address of the label, undefined ones are collected for the link error
+    pub fn resolve(&mut self, label: &str) -> AddrType {
+        match self.labels.read(label) {
+            Some(line) => line,
+            None => {
+                if !self.undefined.iter().any(|undefined| undefined == label) {
+                    self.undefined.push(String::from(label));
+                }
+                0
+            },
+        }
+    }
+
+    pub fn slot(&mut self, name: &str) -> SlotType {
+        if let Some(slot) = self.slots.read(name) {
+            return slot;
+        }
+        let slot = self.variables.len();
+        self.slots.write(name, slot);
+        self.variables.push(String::from(name));
+        slot
+    }
+
+    pub fn refer(&mut self, label: &str) {
+        self.references.push((self.line, String::from(label)));
+    }
+
+    pub fn report(&mut self, message: String) {
+        self.violations.push((self.line, message));
     }
 
     pub fn push_loop(&mut self) {
-        self.loop_stack.push(self.line);
+        self.blocks.push(("LOOP", self.line));
     }
 
-    pub fn consume_loop(&mut self) {
-        let loopstart = self.loop_stack.pop().unwrap();
+    // None, and a violation, when the innermost open block is no LOOP
+    pub fn consume_loop(&mut self) -> Option<AddrType> {
+        let loopstart = self.consume_block("LOOP", "ENDLOOP")?;
         let endloop = self.line;
         self.loops.push((loopstart, endloop));
+        Some(loopstart)
     }
 
     pub fn push_while(&mut self) {
-        self.while_stack.push(self.line);
+        self.blocks.push(("WHILE", self.line));
     }
 
-    pub fn consume_while(&mut self) {
-        let whilestart = self.while_stack.pop().unwrap();
+    pub fn consume_while(&mut self) -> Option<AddrType> {
+        let whilestart = self.consume_block("WHILE", "ENDWHILE")?;
         let endwhile = self.line;
         self.whiles.push((whilestart, endwhile));
+        Some(whilestart)
+    }
+
+    fn consume_block(&mut self, kind: &str, end: &str) -> Option<AddrType> {
+        match self.blocks.last() {
+            Some((open, start)) if *open == kind => {
+                let start = *start;
+                self.blocks.pop();
+                Some(start)
+            },
+            Some((open, _)) => {
+                let message = format!("{} closes a {}", end, open);
+                self.report(message);
+                None
+            },
+            None => {
+                self.report(format!("{} without a {}", end, kind));
+                None
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkError {
+    pub file: Option<String>,
+    pub undefined: Vec<String>,
+    pub violations: Vec<Violation>,
+}
+
+impl LinkError {
+    // Link only sees the source, the caller knows where it came from
+    pub fn set_file(&mut self, file: &str) {
+        self.file = Some(String::from(file));
+        for violation in &mut self.violations {
+            violation.file = Some(String::from(file));
+        }
     }
 }
 
-impl Default for CodeContext {
-    fn default() -> Self {
-        Self {
-            line: Default::default(),
-            start: Default::default(),
-            labels: Default::default(),
-            loops: Vec::new(),
-            whiles: Vec::new(),
-            loop_stack: Stack::new(),
-            while_stack: Stack::new(),
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut errors: Vec<String> = Vec::new();
+        if !self.undefined.is_empty() {
+            let labels: Vec<String> = self.undefined.iter()
+                    .map(|label| format!("'{}'", label))
+                    .collect();
+            let undefined = format!("undefined labels {}", labels.join(", "));
+            errors.push(match &self.file {
+                Some(file) => format!("{}: {}", file, undefined),
+                None => undefined,
+            });
         }
+        errors.extend(self.violations.iter().map(|violation| violation.to_string()));
+        write!(f, "{}", errors.join("\n"))
     }
 }
 
 pub struct ByteCode {
-    labels: VariableTable<AddrType>,
-    exprs: Vec<Box<dyn expr::Expr>>,
-    start_addr: AddrType,
+    pub(crate) labels: VariableTable<AddrType>,
+    // Variable names by slot, for debugging
+    pub(crate) variables: Vec<String>,
+    pub(crate) exprs: Vec<Instruction>,
+    pub(crate) start_addr: AddrType,
+    // Found while linking, saved byte code has none
+    pub(crate) warnings: Vec<Warning>,
+    // Source of the instructions, by address. Empty for byte code saved
+    // without debug info
+    pub(crate) file: Option<String>,
+    pub(crate) positions: Vec<SourcePos>,
 }
 
 impl ByteCode {
@@ -82,35 +192,123 @@ impl ByteCode {
         let mut file_content: String = String::new();
         File::open(path).unwrap().read_to_string(&mut file_content).unwrap();
 
-        let mut lines: Vec<String> = file_content.lines()
-                .filter(|line| !line.trim().is_empty())
-                .map(|s| s.trim().to_owned())
-                .collect();
+        let mut byte_code = Self::from_source(&file_content);
+        byte_code.set_file(path);
+        byte_code
+    }
+
+    pub fn from_source(file_content: &str) -> Self {
+        Self::link(file_content).unwrap_or_else(|err| panic!("Link error: {}", err))
+    }
+
+    // Parses the source and resolves every label reference to its address
+    pub fn link(file_content: &str) -> Result<Self, LinkError> {
+        Self::link_with(file_content, &Default::default())
+    }
+
+    // Like link, with the host instructions registered in parse_engine
+    pub fn link_with(file_content: &str, parse_engine: &ParseEngine) -> Result<Self, LinkError> {
+        // Everything after a ';' is a comment
+        let (positions, lines): (Vec<SourcePos>, Vec<&str>) = file_content.lines()
+                .enumerate()
+                .map(|(i, line)| {
+                    let code = line.split(';').next().unwrap();
+                    let column = code.chars().take_while(|c| c.is_whitespace()).count() + 1;
+                    (SourcePos { line: i + 1, column }, code.trim())
+                })
+                .filter(|(_, line)| !line.is_empty())
+                .unzip();
 
-        let parse_engine: ParseEngine = Default::default();
         let mut code_context: CodeContext = Default::default();
-        let mut ctx = &mut code_context;
-        let mut exprs: Vec<Box<dyn expr::Expr>> = lines.drain(..)
+        let ctx = &mut code_context;
+        let mut exprs: Vec<Instruction> = lines.into_iter()
                 .enumerate()
                 .map(|(i, s)| {
                     ctx.set_line(i);
-                    parse_engine.parse(&s, &mut ctx)
+                    parse_engine.parse(s, ctx).unwrap_or_else(|message| {
+                        ctx.report(message);
+                        Instruction::Noop
+                    })
                 })
                 .collect();
+        for (kind, start) in std::mem::take(&mut ctx.blocks) {
+            ctx.violations.push((start, format!("{} is never closed", kind)));
+        }
 
+        for (line, label) in std::mem::take(&mut ctx.references) {
+            let addr = ctx.resolve(&label);
+            exprs[line].set_target(addr);
+        }
+        for (start, end) in ctx.loops.iter().chain(&ctx.whiles) {
+            exprs[*start].set_target(*end);
+        }
         for expr in &mut exprs {
-            expr.init(ctx);
+            if let Instruction::Host(expr) = expr {
+                expr.init(ctx);
+            }
         }
 
         let start_addr = code_context.start();
+        // Block and label errors leave targets the verifier can not follow
+        let mut violations = code_context.violations;
+        if violations.is_empty() && code_context.undefined.is_empty() {
+            violations = verify::verify(&exprs, start_addr);
+        }
+        if !violations.is_empty() || !code_context.undefined.is_empty() {
+            let mut violations: Vec<Violation> = violations.into_iter()
+                    .map(|(addr, message)| Violation { file: None, pos: positions[addr], message })
+                    .collect();
+            violations.sort_by_key(|violation| (violation.pos.line, violation.pos.column));
+            return Err(LinkError { file: None, undefined: code_context.undefined, violations });
+        }
+
         let labels = code_context.labels;
+        let variables = code_context.variables;
         
-        ByteCode {
+        let mut byte_code = ByteCode {
             //source: source,
             //expressions: expr_iter
-            labels: labels,
-            exprs: exprs,
-            start_addr: start_addr,
+            labels,
+            variables,
+            exprs,
+            start_addr,
+            warnings: Vec::new(),
+            file: None,
+            positions,
+        };
+        byte_code.warnings = byte_code.lint().into_iter()
+                .map(|(addr, message)| Warning { file: None, pos: byte_code.positions[addr], message })
+                .collect();
+        Ok(byte_code)
+    }
+
+    // Problems that do not keep the code from running, in source order
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    // Name of the file the code was loaded from, for messages
+    pub fn set_file(&mut self, file: &str) {
+        self.file = Some(String::from(file));
+        for warning in &mut self.warnings {
+            warning.file = Some(String::from(file));
+        }
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn source_pos(&self, addr: AddrType) -> Option<SourcePos> {
+        self.positions.get(addr).copied()
+    }
+
+    // file:line:column of the instruction at addr
+    pub fn location(&self, addr: AddrType) -> Option<String> {
+        let pos = self.source_pos(addr)?;
+        match &self.file {
+            Some(file) => Some(format!("{}:{}", file, pos)),
+            None => Some(pos.to_string()),
         }
     }
 
@@ -118,6 +316,14 @@ impl ByteCode {
         &self.labels
     }
 
+    pub fn variable_name(&self, slot: SlotType) -> Option<&str> {
+        self.variables.get(slot).map(String::as_str)
+    }
+
+    pub fn variable_slot(&self, name: &str) -> Option<SlotType> {
+        self.variables.iter().position(|variable| variable == name)
+    }
+
     pub fn start_addr(&self) -> AddrType {
         self.start_addr
     }
@@ -126,11 +332,11 @@ impl ByteCode {
         self.exprs.len()
     }
 
-    pub fn get_line<'a>(&'a self, addr: usize) -> Option<&'a Box<dyn expr::Expr>> {
+    pub fn get_line(&self, addr: usize) -> Option<&Instruction> {
         self.exprs.get(addr)
     }
 
-    pub fn get_line_mut<'a>(&'a mut self, addr: usize) -> Option<&'a mut Box<dyn expr::Expr>> {
+    pub fn get_line_mut(&mut self, addr: usize) -> Option<&mut Instruction> {
         self.exprs.get_mut(addr)
     }
 }
\ No newline at end of file
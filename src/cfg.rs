@@ -0,0 +1,217 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::bytecode::{ByteCode, SourcePos};
+use crate::instruction::Instruction;
+use crate::interpreter::AddrType;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    // Filled in by ByteCode::set_file
+    pub file: Option<String>,
+    pub pos: SourcePos,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}: warning: {}", file, self.pos, self.message),
+            None => write!(f, "line {}, column {}: warning: {}",
+                self.pos.line, self.pos.column, self.message),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    // On to the next line
+    Fallthrough,
+    // A jump, or a condition that holds
+    Taken,
+    NotTaken,
+    Call,
+    // Where a LOAD_ADDR points, for the thread spawned with it
+    Spawn,
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Edge::Fallthrough => write!(f, "fallthrough"),
+            Edge::Taken => write!(f, "taken"),
+            Edge::NotTaken => write!(f, "not taken"),
+            Edge::Call => write!(f, "call"),
+            Edge::Spawn => write!(f, "spawn"),
+        }
+    }
+}
+
+// Lines start..end, only entered at start and only left at end - 1
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasicBlock {
+    pub start: AddrType,
+    pub end: AddrType,
+    // Starts of the blocks control can go to next
+    pub successors: Vec<(AddrType, Edge)>,
+}
+
+pub struct ControlFlowGraph {
+    // By start address
+    pub blocks: Vec<BasicBlock>,
+    // Blocks a thread can get to from START
+    pub reachable: BTreeSet<AddrType>,
+}
+
+// Lines control goes to after addr, the end of the code is left out.
+// CALL goes on at its return line once the callee returns. LOOP and WHILE
+// take the exit once they are done
+fn successors(expr: &Instruction, addr: AddrType) -> Vec<(AddrType, Edge)> {
+    let next = addr + 1;
+    match expr {
+        Instruction::Jump(line) => vec![(*line, Edge::Taken)],
+        Instruction::JumpZero(line) => vec![(*line, Edge::Taken), (next, Edge::NotTaken)],
+        Instruction::Call { func_line, return_line } =>
+            vec![(*func_line, Edge::Call), (*return_line, Edge::Fallthrough)],
+        Instruction::Return | Instruction::ReturnValue => Vec::new(),
+        Instruction::Loop { endloop, .. } => vec![(endloop + 1, Edge::Taken), (next, Edge::NotTaken)],
+        Instruction::EndLoop { loopstart, .. } => vec![(*loopstart, Edge::Taken)],
+        Instruction::While { endwhile } => vec![(endwhile + 1, Edge::Taken), (next, Edge::NotTaken)],
+        Instruction::EndWhile { whilestart } => vec![(*whilestart, Edge::Taken)],
+        Instruction::LoadAddr(line) => vec![(next, Edge::Fallthrough), (*line, Edge::Spawn)],
+        _ => vec![(next, Edge::Fallthrough)],
+    }
+}
+
+impl ByteCode {
+    // Blocks are split at every LABEL and at every line that jumps, loops or
+    // calls, a LABEL starts the block of the line it names
+    pub fn control_flow_graph(&self) -> ControlFlowGraph {
+        let len = self.exprs.len();
+        let block_of = |mut addr: AddrType| {
+            while addr > 0 && matches!(self.exprs.get(addr - 1), Some(Instruction::Label)) {
+                addr -= 1;
+            }
+            addr
+        };
+
+        let mut leaders = BTreeSet::from([0, block_of(self.start_addr)]);
+        for (addr, expr) in self.exprs.iter().enumerate() {
+            let targets = successors(expr, addr);
+            if targets != [(addr + 1, Edge::Fallthrough)] {
+                leaders.insert(addr + 1);
+                leaders.extend(targets.into_iter().map(|(line, _)| block_of(line)));
+            }
+            if let Instruction::Label = expr {
+                leaders.insert(block_of(addr));
+            }
+        }
+        let leaders: Vec<AddrType> = leaders.into_iter().filter(|addr| *addr < len).collect();
+
+        let blocks: Vec<BasicBlock> = leaders.iter().enumerate()
+                .map(|(i, start)| {
+                    let end = leaders.get(i + 1).copied().unwrap_or(len);
+                    let successors = successors(&self.exprs[end - 1], end - 1).into_iter()
+                            .filter(|(line, _)| *line < len)
+                            .map(|(line, edge)| (block_of(line), edge))
+                            .collect();
+                    BasicBlock { start: *start, end, successors }
+                })
+                .collect();
+
+        let mut reachable = BTreeSet::new();
+        let mut work = Vec::new();
+        if len > 0 {
+            work.push(block_of(self.start_addr));
+        }
+        while let Some(start) = work.pop() {
+            if reachable.insert(start) {
+                let block = &blocks[leaders.binary_search(&start).unwrap()];
+                work.extend(block.successors.iter().map(|(line, _)| *line));
+            }
+        }
+
+        ControlFlowGraph { blocks, reachable }
+    }
+
+    // Unreachable code, unused labels and variables that are never read or
+    // never written, by address. None of them keep the code from running
+    pub(crate) fn lint(&self) -> Vec<(AddrType, String)> {
+        let graph = self.control_flow_graph();
+        let mut warnings = Vec::new();
+        let unreachable = |count| match count {
+            1 => String::from("unreachable instruction is never run"),
+            count => format!("{} unreachable instructions are never run", count),
+        };
+
+        // LABEL, NOOP and START lines do nothing, so a dead one is no news
+        let mut dead_run: Option<(AddrType, usize)> = None;
+        for block in graph.blocks.iter().filter(|block| !graph.reachable.contains(&block.start)) {
+            for addr in block.start..block.end {
+                if matches!(self.exprs[addr], Instruction::Label | Instruction::Noop | Instruction::Start) {
+                    continue;
+                }
+                match &mut dead_run {
+                    Some((_, count)) => *count += 1,
+                    None => dead_run = Some((addr, 1)),
+                }
+            }
+            let next_reachable = graph.reachable.contains(&block.end) || block.end == self.exprs.len();
+            if next_reachable {
+                if let Some((addr, count)) = dead_run.take() {
+                    warnings.push((addr, unreachable(count)));
+                }
+            }
+        }
+        if let Some((addr, count)) = dead_run {
+            warnings.push((addr, unreachable(count)));
+        }
+
+        let mut targets = BTreeSet::new();
+        for expr in &self.exprs {
+            match expr {
+                Instruction::Jump(line) | Instruction::JumpZero(line)
+                    | Instruction::LoadAddr(line)
+                    | Instruction::Call { func_line: line, .. } => { targets.insert(*line); },
+                _ => {},
+            }
+        }
+        for (label, addr) in self.labels.iter() {
+            if !targets.contains(&addr) {
+                warnings.push((addr - 1, format!("label '{}' is never used", label)));
+            }
+        }
+
+        // A host instruction may read and write any variable
+        if !self.exprs.iter().any(|expr| matches!(expr, Instruction::Host(_))) {
+            let mut written = vec![None; self.variables.len()];
+            let mut read = vec![None; self.variables.len()];
+            for (addr, expr) in self.exprs.iter().enumerate() {
+                match expr {
+                    Instruction::WriteVar(slot) | Instruction::TeeVar(slot) => {
+                        written[*slot].get_or_insert(addr);
+                    },
+                    Instruction::ReadVar(slot) => { read[*slot].get_or_insert(addr); },
+                    _ => {},
+                }
+            }
+            for (slot, name) in self.variables.iter().enumerate() {
+                // LOOP keeps its counters in variables of its own
+                if name.starts_with("_'") {
+                    continue;
+                }
+                match (written[slot], read[slot]) {
+                    (Some(addr), None) =>
+                        warnings.push((addr, format!("variable '{}' is never read", name))),
+                    (None, Some(addr)) =>
+                        warnings.push((addr, format!("variable '{}' is never written", name))),
+                    _ => {},
+                }
+            }
+        }
+
+        warnings.sort();
+        warnings
+    }
+}
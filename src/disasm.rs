@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::bytecode::ByteCode;
+use crate::instruction::Instruction;
+use crate::interpreter::{AddrType, SlotType};
+
+
+// Column the address comments start at
+const COMMENT_COLUMN: usize = 28;
+
+impl ByteCode {
+    // Canonical .br text of the loaded code, each line commented with its
+    // address and resolved targets. Assembling it again gives the same code
+    pub fn disassemble(&self) -> String {
+        let labels = self.labels_by_addr();
+        let mut text = String::new();
+        writeln!(text, "; start {:04}", self.start_addr).unwrap();
+        for addr in 0..self.exprs.len() {
+            match self.instruction_text(addr, &labels) {
+                (line, Some(note)) => writeln!(text, "{:width$}; {:04} {}", line, addr, note,
+                    width = COMMENT_COLUMN).unwrap(),
+                (line, None) => writeln!(text, "{:width$}; {:04}", line, addr,
+                    width = COMMENT_COLUMN).unwrap(),
+            }
+        }
+        text
+    }
+
+    // A label names the line after its LABEL
+    pub(crate) fn labels_by_addr(&self) -> BTreeMap<AddrType, &str> {
+        self.labels.iter()
+                .map(|(label, addr)| (addr, label))
+                .collect()
+    }
+
+    // Source text of the line at addr and a note on what it resolved to
+    pub(crate) fn instruction_text(&self, addr: AddrType, labels: &BTreeMap<AddrType, &str>)
+        -> (String, Option<String>)
+    {
+        let label = |addr: &AddrType| match labels.get(addr) {
+            Some(label) => format!("'{}'", label),
+            None => format!("'?{}'", addr),
+        };
+        let variable = |slot: &SlotType| match self.variable_name(*slot) {
+            Some(name) => format!("'{}'", name),
+            None => format!("'slot{}'", slot),
+        };
+
+        match &self.exprs[addr] {
+            Instruction::Noop => (String::from("NOOP"), None),
+            Instruction::Start => (String::from("START"), None),
+            Instruction::Label => (format!("LABEL {}", label(&(addr + 1))), None),
+            Instruction::LoadVal(val) => (format!("LOAD_VAL {}", val), None),
+            Instruction::WriteVar(slot) =>
+                (format!("WRITE_VAR {}", variable(slot)), Some(format!("slot {}", slot))),
+            Instruction::ReadVar(slot) =>
+                (format!("READ_VAR {}", variable(slot)), Some(format!("slot {}", slot))),
+            Instruction::TeeVar(slot) =>
+                (format!("TEE_VAR {}", variable(slot)), Some(format!("slot {}", slot))),
+            Instruction::Pop => (String::from("POP"), None),
+            Instruction::Add => (String::from("ADD"), None),
+            Instruction::Multiply => (String::from("MULTIPLY"), None),
+            Instruction::Subtract => (String::from("SUBTRACT"), None),
+            Instruction::Equal => (String::from("EQUAL"), None),
+            Instruction::Call { func_line, return_line } => (
+                format!("CALL {}", label(func_line)),
+                Some(format!("-> {:04}, returns to {:04}", func_line, return_line)),
+            ),
+            Instruction::Return => (String::from("RETURN"), None),
+            Instruction::ReturnValue => (String::from("RETURN_VALUE"), None),
+            Instruction::Jump(line) =>
+                (format!("JUMP {}", label(line)), Some(format!("-> {:04}", line))),
+            Instruction::JumpZero(line) =>
+                (format!("JUMP_ZERO {}", label(line)), Some(format!("-> {:04}", line))),
+            Instruction::Loop { endloop, .. } =>
+                (String::from("LOOP"), Some(format!("exits to {:04}", endloop + 1))),
+            Instruction::EndLoop { loopstart, .. } =>
+                (String::from("ENDLOOP"), Some(format!("-> {:04}", loopstart))),
+            Instruction::While { endwhile } =>
+                (String::from("WHILE"), Some(format!("exits to {:04}", endwhile + 1))),
+            Instruction::EndWhile { whilestart } =>
+                (String::from("ENDWHILE"), Some(format!("-> {:04}", whilestart))),
+            Instruction::LoadAddr(line) =>
+                (format!("LOAD_ADDR {}", label(line)), Some(format!("= {:04}", line))),
+            Instruction::LoadChannel(channel) => (format!("LOAD_CHANNEL {}", channel), None),
+            Instruction::SendChannel => (String::from("SEND_CHANNEL"), None),
+            Instruction::RecvChannel => (String::from("RECV_CHANNEL"), None),
+            Instruction::SendTo => (String::from("SEND_TO"), None),
+            Instruction::Receive => (String::from("RECEIVE"), None),
+            Instruction::Spawn(0) => (String::from("SPAWN"), None),
+            Instruction::Spawn(argc) => (format!("SPAWN {}", argc), None),
+            Instruction::SpawnN(count) => (format!("SPAWN_N {}", count), None),
+            Instruction::Join => (String::from("JOIN"), None),
+            Instruction::SetPriority => (String::from("SET_PRIORITY"), None),
+            Instruction::Yield => (String::from("YIELD"), None),
+            Instruction::Sleep(ticks) => (format!("SLEEP {}", ticks), None),
+            Instruction::Now => (String::from("NOW"), None),
+            Instruction::ThreadId => (String::from("THREAD_ID"), None),
+            Instruction::ThreadCount => (String::from("THREAD_COUNT"), None),
+            Instruction::SetThreadName(name) => (format!("SET_THREAD_NAME '{}'", name), None),
+            Instruction::Kill => (String::from("KILL"), None),
+            Instruction::Cancel => (String::from("CANCEL"), None),
+            Instruction::CheckCancel => (String::from("CHECK_CANCEL"), None),
+            Instruction::Monitor => (String::from("MONITOR"), None),
+            Instruction::ReadGlobal(name) => (format!("READ_GLOBAL '{}'", name), None),
+            Instruction::WriteGlobal(name) => (format!("WRITE_GLOBAL '{}'", name), None),
+            Instruction::AtomicAdd(name) => (format!("ATOMIC_ADD '{}'", name), None),
+            Instruction::CompareAndSwap(name) => (format!("COMPARE_AND_SWAP '{}'", name), None),
+            Instruction::FetchAndSet(name) => (format!("FETCH_AND_SET '{}'", name), None),
+            Instruction::Lock(mutex) => (format!("LOCK '{}'", mutex), None),
+            Instruction::Unlock(mutex) => (format!("UNLOCK '{}'", mutex), None),
+            Instruction::Wait(mutex) => (format!("WAIT '{}'", mutex), None),
+            Instruction::Notify(mutex) => (format!("NOTIFY '{}'", mutex), None),
+            Instruction::NotifyAll(mutex) => (format!("NOTIFY_ALL '{}'", mutex), None),
+            // Only assembles again with the host instruction registered
+            // under its name
+            Instruction::Host(expr) => (String::from(expr.name()), Some(String::from("host"))),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt::Write;
+
+use crate::bytecode::ByteCode;
+
+
+impl ByteCode {
+    // The control flow graph in Graphviz DOT, one box per basic block
+    // listing its lines. The block START runs has a double border,
+    // unreachable blocks are dashed
+    pub fn to_dot(&self) -> String {
+        let graph = self.control_flow_graph();
+        let labels = self.labels_by_addr();
+        let escape = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+
+        let mut dot = String::new();
+        writeln!(dot, "digraph bytecode {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        for block in &graph.blocks {
+            let mut lines = String::new();
+            for addr in block.start..block.end {
+                let (line, _) = self.instruction_text(addr, &labels);
+                write!(lines, "{:04}  {}\\l", addr, escape(&line)).unwrap();
+            }
+            let mut style = String::new();
+            if block.start <= self.start_addr && self.start_addr < block.end {
+                style.push_str(", peripheries=2");
+            }
+            if !graph.reachable.contains(&block.start) {
+                style.push_str(", style=dashed");
+            }
+            writeln!(dot, "    b{:04} [label=\"{}\"{}];", block.start, lines, style).unwrap();
+        }
+        for block in &graph.blocks {
+            for (successor, edge) in &block.successors {
+                writeln!(dot, "    b{:04} -> b{:04} [label=\"{}\"];", block.start, successor, edge)
+                        .unwrap();
+            }
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::bytecode::ByteCode;
+use crate::interpreter::{Interpreter, RunOutcome, ThreadExit, ThreadIdType};
+use crate::scheduler::{PctScheduler, RandomScheduler, ReplayScheduler, Scheduler};
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Schedule {
+    Random(u64),
+    Pct { seed: u64, depth: usize, max_steps: usize },
+    // Index into the runnable threads at every scheduling point
+    Decisions(Vec<usize>),
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Random(seed) => write!(f, "random seed {}", seed),
+            Schedule::Pct { seed, depth, max_steps } =>
+                write!(f, "pct seed {} depth {} steps {}", seed, depth, max_steps),
+            Schedule::Decisions(choices) => write!(f, "decisions {:?}", choices),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Failure {
+    Deadlock(Vec<ThreadIdType>),
+    // Thread, its name, the location of the instruction and the trap message
+    Trapped(ThreadIdType, Option<String>, Option<String>, String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Deadlock(threads) => write!(f, "deadlock of threads {:?}", threads),
+            Failure::Trapped(id, name, location, msg) => {
+                write!(f, "thread {}", id)?;
+                if let Some(name) = name {
+                    write!(f, " '{}'", name)?;
+                }
+                match location {
+                    Some(location) => write!(f, " trapped at {}: {}", location, msg),
+                    None => write!(f, " trapped: {}", msg),
+                }
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub schedule: Schedule,
+    pub failure: Failure,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} with {}", self.failure, self.schedule)
+    }
+}
+
+pub struct Explorer<F: Fn() -> ByteCode> {
+    load: F,
+}
+
+impl<F: Fn() -> ByteCode> Explorer<F> {
+    pub fn new(load: F) -> Self {
+        Explorer { load }
+    }
+
+    pub fn explore_random(&self, first_seed: u64, runs: u64) -> Vec<Report> {
+        let schedules = (first_seed..first_seed + runs).map(Schedule::Random);
+        self.explore(schedules)
+    }
+
+    pub fn explore_pct(&self, first_seed: u64, runs: u64, depth: usize) -> Vec<Report> {
+        // Number of scheduling points in an unperturbed run
+        let max_steps = self.decisions(Vec::new()).len();
+        let schedules = (first_seed..first_seed + runs)
+                .map(|seed| Schedule::Pct { seed, depth, max_steps });
+        self.explore(schedules)
+    }
+
+    // Depth first search over the choices at the first max_depth scheduling
+    // points, stopping after max_runs runs
+    pub fn explore_dfs(&self, max_depth: usize, max_runs: usize) -> Vec<Report> {
+        let mut reports = Vec::new();
+        let mut prefix = Vec::new();
+        for _ in 0..max_runs {
+            let decisions = Arc::new(Mutex::new(Vec::new()));
+            let scheduler = ReplayScheduler::new(prefix, decisions.clone());
+            let failure = self.run_with(Box::new(scheduler)).1;
+
+            let decisions = decisions.lock().unwrap();
+            if let Some(failure) = failure {
+                let choices = decisions.iter().map(|(choice, _)| *choice).collect();
+                let report = Report { schedule: Schedule::Decisions(choices), failure };
+                reports.push(report);
+            }
+
+            let backtrack = decisions.iter()
+                    .take(max_depth)
+                    .rposition(|(choice, count)| choice + 1 < *count);
+            match backtrack {
+                Some(i) => {
+                    prefix = decisions[..i].iter().map(|(choice, _)| *choice).collect();
+                    prefix.push(decisions[i].0 + 1);
+                },
+                None => break,
+            }
+        }
+        reports
+    }
+
+    pub fn replay(&self, schedule: &Schedule) -> (Interpreter, Option<Failure>) {
+        let scheduler: Box<dyn Scheduler> = match schedule {
+            Schedule::Random(seed) => Box::new(RandomScheduler::new(*seed, 1)),
+            Schedule::Pct { seed, depth, max_steps } =>
+                Box::new(PctScheduler::new(*seed, *depth, *max_steps)),
+            Schedule::Decisions(choices) => {
+                let decisions = Arc::new(Mutex::new(Vec::new()));
+                Box::new(ReplayScheduler::new(choices.clone(), decisions))
+            },
+        };
+        self.run_with(scheduler)
+    }
+
+    fn explore(&self, schedules: impl Iterator<Item = Schedule>) -> Vec<Report> {
+        let mut reports = Vec::new();
+        for schedule in schedules {
+            if let (_, Some(failure)) = self.replay(&schedule) {
+                let report = Report { schedule, failure };
+                reports.push(report);
+            }
+        }
+        reports
+    }
+
+    fn decisions(&self, prefix: Vec<usize>) -> Vec<(usize, usize)> {
+        let decisions = Arc::new(Mutex::new(Vec::new()));
+        self.run_with(Box::new(ReplayScheduler::new(prefix, decisions.clone())));
+        let decisions = decisions.lock().unwrap().clone();
+        decisions
+    }
+
+    fn run_with(&self, scheduler: Box<dyn Scheduler>) -> (Interpreter, Option<Failure>) {
+        let mut interpreter = Interpreter::new((self.load)());
+        interpreter.set_scheduler(scheduler);
+        let outcome = interpreter.run();
+
+        let trap = interpreter.thread_exits().iter()
+                .find_map(|(id, exit)| match exit {
+                    ThreadExit::Trapped(msg) => {
+                        let name = interpreter.thread_name(*id).map(String::from);
+                        let location = interpreter.trap_location(*id);
+                        Some(Failure::Trapped(*id, name, location, msg.clone()))
+                    },
+                    ThreadExit::Returned(_) | ThreadExit::Cancelled => None,
+                });
+        let failure = match outcome {
+            _ if trap.is_some() => trap,
+            RunOutcome::Deadlock => Some(Failure::Deadlock(interpreter.live_threads())),
+            RunOutcome::Finished | RunOutcome::StepLimit => None,
+        };
+        (interpreter, failure)
+    }
+}
@@ -0,0 +1,384 @@
+use crate::expr::Expr;
+use crate::interpreter::{
+    AddrType, ControlFlow, SlotType, Stack, StackItem, ThreadIdType, Trap, ValueType,
+    VariableSlots, VariableTable
+};
+
+
+// A line of byte code with its operands resolved by the loader
+pub enum Instruction {
+    Noop,
+    Start,
+    Label,
+    LoadVal(ValueType),
+    WriteVar(SlotType),
+    ReadVar(SlotType),
+    // Writes the top of the stack without popping it
+    TeeVar(SlotType),
+    // Drops the top of the stack, whatever it is
+    Pop,
+    Add,
+    Multiply,
+    // The value under the top minus the top
+    Subtract,
+    // 1 when the two values on top are the same, 0 otherwise
+    Equal,
+    Call { func_line: AddrType, return_line: AddrType },
+    Return,
+    ReturnValue,
+    Jump(AddrType),
+    // Jump if stack.top() == Some(Value(0))
+    JumpZero(AddrType),
+    // Loop 0..{stack.top() (= Some(Value(end)))}
+    Loop { loop_var: SlotType, count_var: SlotType, endloop: AddrType },
+    EndLoop { loop_var: SlotType, loopstart: AddrType },
+    // While stack.top() != Some(Value(0))
+    While { endwhile: AddrType },
+    EndWhile { whilestart: AddrType },
+    LoadAddr(AddrType),
+    LoadChannel(ValueType),
+    SendChannel,
+    RecvChannel,
+    SendTo,
+    Receive,
+    // Spawn {stack.pop() (= Addr)} with the next argc values as its arguments
+    Spawn(usize),
+    // Spawn each of the top count Addrs without arguments
+    SpawnN(usize),
+    Join,
+    SetPriority,
+    Yield,
+    Sleep(ValueType),
+    Now,
+    ThreadId,
+    ThreadCount,
+    SetThreadName(String),
+    Kill,
+    Cancel,
+    CheckCancel,
+    Monitor,
+    ReadGlobal(String),
+    WriteGlobal(String),
+    // Atomic read-modify-writes on globals, a missing global counts as 0
+    AtomicAdd(String),
+    CompareAndSwap(String),
+    FetchAndSet(String),
+    Lock(String),
+    Unlock(String),
+    Wait(String),
+    Notify(String),
+    NotifyAll(String),
+    // Custom instruction registered by the host
+    Host(Box<dyn Expr>),
+}
+
+impl Instruction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instruction::Noop => "Noop",
+            Instruction::Start => "Start",
+            Instruction::Label => "Label",
+            Instruction::LoadVal(_) => "LoadVal",
+            Instruction::WriteVar(_) => "WriteVar",
+            Instruction::ReadVar(_) => "ReadVar",
+            Instruction::TeeVar(_) => "TeeVar",
+            Instruction::Pop => "Pop",
+            Instruction::Add => "Add",
+            Instruction::Multiply => "Multiply",
+            Instruction::Subtract => "Subtract",
+            Instruction::Equal => "Equal",
+            Instruction::Call { .. } => "Call",
+            Instruction::Return => "Return",
+            Instruction::ReturnValue => "ReturnValue",
+            Instruction::Jump(_) => "Jump",
+            Instruction::JumpZero(_) => "JumpZero",
+            Instruction::Loop { .. } => "Loop",
+            Instruction::EndLoop { .. } => "EndLoop",
+            Instruction::While { .. } => "While",
+            Instruction::EndWhile { .. } => "EndWhile",
+            Instruction::LoadAddr(_) => "LoadAddr",
+            Instruction::LoadChannel(_) => "LoadChannel",
+            Instruction::SendChannel => "SendChannel",
+            Instruction::RecvChannel => "RecvChannel",
+            Instruction::SendTo => "SendTo",
+            Instruction::Receive => "Receive",
+            Instruction::Spawn(_) => "Spawn",
+            Instruction::SpawnN(_) => "SpawnN",
+            Instruction::Join => "Join",
+            Instruction::SetPriority => "SetPriority",
+            Instruction::Yield => "Yield",
+            Instruction::Sleep(_) => "Sleep",
+            Instruction::Now => "Now",
+            Instruction::ThreadId => "ThreadId",
+            Instruction::ThreadCount => "ThreadCount",
+            Instruction::SetThreadName(_) => "SetThreadName",
+            Instruction::Kill => "Kill",
+            Instruction::Cancel => "Cancel",
+            Instruction::CheckCancel => "CheckCancel",
+            Instruction::Monitor => "Monitor",
+            Instruction::ReadGlobal(_) => "ReadGlobal",
+            Instruction::WriteGlobal(_) => "WriteGlobal",
+            Instruction::AtomicAdd(_) => "AtomicAdd",
+            Instruction::CompareAndSwap(_) => "CompareAndSwap",
+            Instruction::FetchAndSet(_) => "FetchAndSet",
+            Instruction::Lock(_) => "Lock",
+            Instruction::Unlock(_) => "Unlock",
+            Instruction::Wait(_) => "Wait",
+            Instruction::Notify(_) => "Notify",
+            Instruction::NotifyAll(_) => "NotifyAll",
+            Instruction::Host(expr) => expr.name(),
+        }
+    }
+
+    // Whether eval reads or writes thread_global, parallel runs only lock the
+    // globals around the instructions that do
+    pub fn uses_globals(&self) -> bool {
+        match self {
+            Instruction::ReadGlobal(_) | Instruction::WriteGlobal(_)
+                | Instruction::AtomicAdd(_) | Instruction::CompareAndSwap(_)
+                | Instruction::FetchAndSet(_) => true,
+            Instruction::Host(expr) => expr.uses_globals(),
+            _ => false,
+        }
+    }
+
+    // Points a label reference, or the start of a block at its end, to addr
+    pub(crate) fn set_target(&mut self, addr: AddrType) {
+        match self {
+            Instruction::Call { func_line: target, .. }
+                | Instruction::Jump(target)
+                | Instruction::JumpZero(target)
+                | Instruction::LoadAddr(target)
+                | Instruction::Loop { endloop: target, .. }
+                | Instruction::While { endwhile: target } => *target = addr,
+            _ => panic!("{} has no target", self.name()),
+        }
+    }
+
+    pub fn eval(&self,
+        thread_global: &mut VariableTable<ValueType>,
+        stack: &mut Stack<StackItem>,
+        var_table: &mut VariableSlots<ValueType>,
+        func_table: &VariableTable<AddrType>,
+        control_flow: &mut ControlFlow
+    ) -> Result<(), Trap> {
+        match self {
+            Instruction::Noop | Instruction::Start | Instruction::Label => {},
+            Instruction::LoadVal(literal) => stack.push(StackItem::Value(*literal)),
+            Instruction::WriteVar(slot) => {
+                let val = stack.pop_value()?;
+                var_table.write(*slot, val);
+            },
+            Instruction::ReadVar(slot) => {
+                let val = var_table.read(*slot).ok_or(Trap::UnsetVariable(*slot))?;
+                stack.push(StackItem::Value(val));
+            },
+            Instruction::TeeVar(slot) => {
+                let val = stack.top_value()?;
+                var_table.write(*slot, val);
+            },
+            Instruction::Pop => {
+                stack.pop_item()?;
+            },
+            // Overflow traps in every build profile
+            Instruction::Add => {
+                let val1 = stack.pop_value()?;
+                let val2 = stack.pop_value()?;
+                match val1.checked_add(val2) {
+                    Some(val) => stack.push(StackItem::Value(val)),
+                    None => return Err(Trap::from("Add overflows")),
+                }
+            },
+            Instruction::Multiply => {
+                let val1 = stack.pop_value()?;
+                let val2 = stack.pop_value()?;
+                match val1.checked_mul(val2) {
+                    Some(val) => stack.push(StackItem::Value(val)),
+                    None => return Err(Trap::from("Multiply overflows")),
+                }
+            },
+            Instruction::Subtract => {
+                let right = stack.pop_value()?;
+                let left = stack.pop_value()?;
+                match left.checked_sub(right) {
+                    Some(val) => stack.push(StackItem::Value(val)),
+                    None => return Err(Trap::from("Subtract overflows")),
+                }
+            },
+            Instruction::Equal => {
+                let val1 = stack.pop_value()?;
+                let val2 = stack.pop_value()?;
+                stack.push(StackItem::Value((val1 == val2) as ValueType));
+            },
+            Instruction::Call { func_line, return_line } => {
+                stack.push(StackItem::ReturnAddr(*return_line));
+                *control_flow = ControlFlow::JumpTo(*func_line);
+            },
+            Instruction::Return => {
+                loop {
+                    if let StackItem::ReturnAddr(return_line) = stack.pop_item()? {
+                        *control_flow = ControlFlow::JumpTo(return_line);
+                        return Ok(());
+                    }
+                }
+            },
+            Instruction::ReturnValue => {
+                let return_val = stack.pop_item()?;
+                loop {
+                    if let StackItem::ReturnAddr(return_line) = stack.pop_item()? {
+                        stack.push(return_val);
+                        *control_flow = ControlFlow::JumpTo(return_line);
+                        return Ok(());
+                    }
+                }
+            },
+            Instruction::Jump(line) => *control_flow = ControlFlow::JumpTo(*line),
+            Instruction::JumpZero(line) => {
+                if let Some(StackItem::Value(0)) = stack.top() {
+                    *control_flow = ControlFlow::JumpTo(*line);
+                }
+            },
+            Instruction::Loop { loop_var, count_var, endloop } => {
+                match var_table.read(*loop_var) {
+                    Some(var) => {
+                        if var >= var_table.read(*count_var).unwrap() {
+                            var_table.remove(*loop_var);
+                            var_table.remove(*count_var);
+                            *control_flow = ControlFlow::JumpTo(endloop + 1);
+                        }
+                    },
+                    None => {
+                        let count = stack.pop_value()?;
+                        var_table.write(*count_var, count);
+                        var_table.write(*loop_var, 0);
+                    },
+                }
+            },
+            Instruction::EndLoop { loop_var, loopstart } => {
+                let loop_var_val = var_table.read(*loop_var)
+                        .ok_or("ENDLOOP runs outside of its LOOP")? + 1;
+                var_table.write(*loop_var, loop_var_val);
+                *control_flow = ControlFlow::JumpTo(*loopstart);
+            },
+            Instruction::While { endwhile } => {
+                match stack.top() {
+                    Some(StackItem::Value(val)) if val != 0 => {},
+                    // Some(Value(0)) | Some(! Value) | None
+                    _ => *control_flow = ControlFlow::JumpTo(endwhile + 1),
+                }
+            },
+            Instruction::EndWhile { whilestart } => *control_flow = ControlFlow::JumpTo(*whilestart),
+            Instruction::LoadAddr(addr) => stack.push(StackItem::Addr(*addr)),
+            Instruction::LoadChannel(channel) => stack.push(StackItem::Channel(*channel)),
+            Instruction::SendChannel => {
+                let channel = stack.pop_channel()?;
+                let value = stack.pop_value()?;
+                *control_flow = ControlFlow::Send(channel, value);
+            },
+            Instruction::RecvChannel => {
+                let channel = stack.pop_channel()?;
+                *control_flow = ControlFlow::Recv(channel);
+            },
+            Instruction::SendTo => {
+                // A handle, or a thread id from THREAD_ID to reply to
+                let id = match stack.pop_item()? {
+                    StackItem::Thread(id) => id,
+                    StackItem::Value(id) => id as ThreadIdType,
+                    _ => return Err(Trap::from("StackItem is not Thread")),
+                };
+                let value = stack.pop_value()?;
+                *control_flow = ControlFlow::SendTo(id, value);
+            },
+            Instruction::Receive => *control_flow = ControlFlow::Receive,
+            Instruction::Spawn(argc) => {
+                let addr = stack.pop_addr()?;
+                let mut args = Vec::with_capacity(*argc);
+                for _ in 0..*argc {
+                    args.push(stack.pop_item()?);
+                }
+                args.reverse();
+                *control_flow = ControlFlow::Spawn(addr, args);
+            },
+            Instruction::SpawnN(count) => {
+                let mut addrs = Vec::with_capacity(*count);
+                for _ in 0..*count {
+                    addrs.push(stack.pop_addr()?);
+                }
+                addrs.reverse();
+                *control_flow = ControlFlow::SpawnN(addrs);
+            },
+            Instruction::Join => {
+                let id = stack.pop_thread()?;
+                *control_flow = ControlFlow::Join(id);
+            },
+            Instruction::SetPriority => {
+                let priority = stack.pop_value()?;
+                *control_flow = ControlFlow::SetPriority(priority);
+            },
+            Instruction::Yield => *control_flow = ControlFlow::Yield,
+            Instruction::Sleep(ticks) => *control_flow = ControlFlow::Sleep(*ticks),
+            Instruction::Now => *control_flow = ControlFlow::Now,
+            Instruction::ThreadId => *control_flow = ControlFlow::ThreadId,
+            Instruction::ThreadCount => *control_flow = ControlFlow::ThreadCount,
+            Instruction::SetThreadName(name) =>
+                *control_flow = ControlFlow::SetThreadName(name.clone()),
+            Instruction::Kill => {
+                let id = stack.pop_thread()?;
+                *control_flow = ControlFlow::Kill(id);
+            },
+            Instruction::Cancel => {
+                let id = stack.pop_thread()?;
+                *control_flow = ControlFlow::Cancel(id);
+            },
+            Instruction::CheckCancel => *control_flow = ControlFlow::CheckCancel,
+            Instruction::Monitor => {
+                let channel = stack.pop_channel()?;
+                let id = stack.pop_thread()?;
+                *control_flow = ControlFlow::Monitor(id, channel);
+            },
+            Instruction::ReadGlobal(name) => {
+                let val = thread_global.read(name)
+                        .ok_or_else(|| format!("Global '{}' is not set", name))?;
+                stack.push(StackItem::Value(val));
+            },
+            Instruction::WriteGlobal(name) => {
+                let val = stack.pop_value()?;
+                thread_global.write(name, val);
+            },
+            Instruction::AtomicAdd(name) => {
+                let delta = stack.pop_value()?;
+                let old = thread_global.read(name).unwrap_or(0);
+                match old.checked_add(delta) {
+                    Some(new) => {
+                        thread_global.write(name, new);
+                        stack.push(StackItem::Value(old));
+                    },
+                    None => return Err(Trap::from(format!("Atomic add to '{}' overflows", name))),
+                }
+            },
+            Instruction::CompareAndSwap(name) => {
+                let new = stack.pop_value()?;
+                let expected = stack.pop_value()?;
+                let old = thread_global.read(name).unwrap_or(0);
+                if old == expected {
+                    thread_global.write(name, new);
+                }
+                stack.push(StackItem::Value(old));
+            },
+            Instruction::FetchAndSet(name) => {
+                let new = stack.pop_value()?;
+                let old = thread_global.read(name).unwrap_or(0);
+                thread_global.write(name, new);
+                stack.push(StackItem::Value(old));
+            },
+            Instruction::Lock(mutex) => *control_flow = ControlFlow::Lock(mutex.clone()),
+            Instruction::Unlock(mutex) => *control_flow = ControlFlow::Unlock(mutex.clone()),
+            Instruction::Wait(mutex) => *control_flow = ControlFlow::Wait(mutex.clone()),
+            Instruction::Notify(mutex) => *control_flow = ControlFlow::Notify(mutex.clone()),
+            Instruction::NotifyAll(mutex) => *control_flow = ControlFlow::NotifyAll(mutex.clone()),
+            Instruction::Host(expr) =>
+                return expr.eval(thread_global, stack, var_table, func_table, control_flow),
+        }
+        Ok(())
+    }
+}
@@ -1,40 +1,95 @@
-use std::collections::HashMap;
-use std::fmt::Debug;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt::{self, Debug};
 
 use crate::bytecode::ByteCode;
+use crate::instruction::Instruction;
+use crate::observer::{ExecutionObserver, Tracer};
+use crate::scheduler::{RoundRobinScheduler, Scheduler, ThreadInfo};
+use crate::sync::SyncTable;
 
 
+pub(crate) enum StackOp<T> {
+    Push(T),
+    Pop(T),
+}
+
 #[derive(Default)]
 pub struct Stack<T> {
-    items: Vec<T>
+    pub(crate) items: Vec<T>,
+    // Pushes and pops in order, only kept while an observer is installed
+    pub(crate) journal: Option<Vec<StackOp<T>>>,
 }
 
 impl<T: Clone + Debug> Stack<T> {
     pub fn new() -> Self {
         Stack {
-            items: vec![]
+            items: vec![],
+            journal: None,
         }
     }
 
     pub fn push(&mut self, item: T) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(StackOp::Push(item.clone()));
+        }
         self.items.push(item);
-        self.print_stack();
     }
 
     pub fn pop(&mut self) -> Option<T> {
-        let ret = self.items.pop();
-        self.print_stack();
-        ret
+        let item = self.items.pop();
+        if let (Some(journal), Some(item)) = (&mut self.journal, &item) {
+            journal.push(StackOp::Pop(item.clone()));
+        }
+        item
     }
 
     pub fn top(&self) -> Option<T> {
-        self.print_stack();
         let item = self.items.last();
-        item.map_or(None, |v| Some(v.to_owned()))
+        item.map(|v| v.to_owned())
+    }
+}
+
+// Pops of the instructions, which trap on an empty stack or an item of the
+// wrong kind
+impl Stack<StackItem> {
+    pub fn pop_item(&mut self) -> Result<StackItem, Trap> {
+        self.pop().ok_or_else(|| Trap::from("Pop from an empty stack"))
+    }
+
+    pub fn pop_value(&mut self) -> Result<ValueType, Trap> {
+        match self.pop_item()? {
+            StackItem::Value(val) => Ok(val),
+            _ => Err(Trap::from("StackItem is not Value")),
+        }
+    }
+
+    pub fn pop_addr(&mut self) -> Result<AddrType, Trap> {
+        match self.pop_item()? {
+            StackItem::Addr(addr) => Ok(addr),
+            _ => Err(Trap::from("StackItem is not Addr")),
+        }
     }
 
-    pub fn print_stack(&self) {
-        println!("{:?}", self.items);
+    pub fn pop_channel(&mut self) -> Result<ValueType, Trap> {
+        match self.pop_item()? {
+            StackItem::Channel(channel) => Ok(channel),
+            _ => Err(Trap::from("StackItem is not Channel")),
+        }
+    }
+
+    pub fn pop_thread(&mut self) -> Result<ThreadIdType, Trap> {
+        match self.pop_item()? {
+            StackItem::Thread(id) => Ok(id),
+            _ => Err(Trap::from("StackItem is not Thread")),
+        }
+    }
+
+    pub fn top_value(&self) -> Result<ValueType, Trap> {
+        match self.items.last() {
+            Some(StackItem::Value(val)) => Ok(*val),
+            Some(_) => Err(Trap::from("StackItem is not Value")),
+            None => Err(Trap::from("Top of an empty stack")),
+        }
     }
 }
 
@@ -58,30 +113,94 @@ impl<T: Copy + Clone> VariableTable<T> {
 
     pub fn read(&self, name: &str) -> Option<T> {
         let val = self.vars.get(name);
-        val.map_or(None, |v| Some(v.to_owned()))
+        val.map(|v| v.to_owned())
     }
 
     pub fn remove(&mut self, name: &str) {
         self.vars.remove(name);
     }
+
+    // In no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, T)> {
+        self.vars.iter().map(|(name, val)| (name.as_str(), *val))
+    }
+}
+
+// Thread variables, indexed by the slot the loader gave their name
+#[derive(Default, Clone)]
+pub struct VariableSlots<T: Clone> {
+    vals: Vec<Option<T>>
+}
+
+impl<T: Copy + Clone> VariableSlots<T> {
+    pub fn write(&mut self, slot: SlotType, val: T) {
+        if slot >= self.vals.len() {
+            self.vals.resize(slot + 1, None);
+        }
+        self.vals[slot] = Some(val);
+    }
+
+    pub fn read(&self, slot: SlotType) -> Option<T> {
+        self.vals.get(slot).copied().flatten()
+    }
+
+    pub fn remove(&mut self, slot: SlotType) {
+        if let Some(val) = self.vals.get_mut(slot) {
+            *val = None;
+        }
+    }
+
+    // The slots that are set, in order
+    pub fn iter(&self) -> impl Iterator<Item = (SlotType, T)> + '_ {
+        self.vals.iter().enumerate().filter_map(|(slot, val)| val.map(|val| (slot, val)))
+    }
 }
 
 pub enum ControlFlow {
     Normal,
+    // Retried after the next event of another thread
     Block,
     JumpTo(usize),
-    Spawn(AddrType, AddrType)
+    Spawn(AddrType, Vec<StackItem>),
+    SpawnN(Vec<AddrType>),
+    Join(ThreadIdType),
+    SetPriority(ValueType),
+    Yield,
+    Sleep(ValueType),
+    Now,
+    ThreadId,
+    ThreadCount,
+    SetThreadName(String),
+    Kill(ThreadIdType),
+    Cancel(ThreadIdType),
+    CheckCancel,
+    Monitor(ThreadIdType, ValueType),
+    Send(ValueType, ValueType),
+    Recv(ValueType),
+    SendTo(ThreadIdType, ValueType),
+    Receive,
+    Lock(String),
+    Unlock(String),
+    Wait(String),
+    Notify(String),
+    NotifyAll(String),
+    Trap(String),
 }
 
 pub type ValueType = i64;
 pub type AddrType = usize;
+pub type SlotType = usize;
+pub type ThreadIdType = usize;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum StackItem {
     Value(ValueType),
     Addr(AddrType),
     ReturnAddr(AddrType),
     Channel(ValueType),
+    Thread(ThreadIdType),
+    Trapped,
+    Cancelled,
 }
 
 impl StackItem {
@@ -112,39 +231,544 @@ impl StackItem {
         }
         panic!("StackItem is not Channel");
     }
+
+    pub fn thread(&self) -> ThreadIdType {
+        if let Self::Thread(id) = self {
+            return *id;
+        }
+        panic!("StackItem is not Thread");
+    }
+}
+
+// Why an instruction could not run, the thread running it exits with it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trap {
+    Message(String),
+    // Named by the thread running it, the instruction only knows the slot
+    UnsetVariable(SlotType),
+}
+
+impl From<String> for Trap {
+    fn from(msg: String) -> Self {
+        Trap::Message(msg)
+    }
+}
+
+impl From<&str> for Trap {
+    fn from(msg: &str) -> Self {
+        Trap::Message(String::from(msg))
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::Message(msg) => write!(f, "{}", msg),
+            Trap::UnsetVariable(slot) => write!(f, "Variable in slot {} is not set", slot),
+        }
+    }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum ThreadExit {
+    // Top of the stack when the thread ran off the end of the code
+    Returned(Option<StackItem>),
+    Trapped(String),
+    // Killed, or stopped at a cancellation check
+    Cancelled,
+}
+
+// Default number of scheduling steps a run may take
+pub const STEP_LIMIT: usize = 1000;
+
 #[allow(dead_code)]
-struct IThread {
-    id: usize,
-    stack: Stack<StackItem>,
-    var_table: VariableTable<ValueType>,
-    addr: AddrType
+pub(crate) struct IThread {
+    id: ThreadIdType,
+    pub(crate) stack: Stack<StackItem>,
+    pub(crate) var_table: VariableSlots<ValueType>,
+    pub(crate) addr: AddrType,
+    priority: ValueType,
+    blocked: bool,
+    wake_at: ValueType,
+    // Released its mutex in WAIT and has not reacquired it yet
+    waiting: bool,
+    mailbox: VecDeque<ValueType>,
 }
 
 impl IThread {
-    fn new(id: usize, addr: AddrType) -> Self {
+    fn new(id: ThreadIdType, addr: AddrType) -> Self {
         IThread {
-            id: id,
+            id,
             stack: Stack::<StackItem>::new(),
             var_table: Default::default(),
-            addr: addr,
+            addr,
+            priority: 0,
+            blocked: false,
+            wake_at: 0,
+            waiting: false,
+            mailbox: VecDeque::new(),
         }
     }
 
-    #[allow(dead_code)]
-    pub fn get_id(&self) -> usize {
+    pub fn get_id(&self) -> ThreadIdType {
         self.id
     }
+
+    // Follows the control flows no other thread can observe
+    pub(crate) fn advance(&mut self, control_flow: &ControlFlow) -> bool {
+        match control_flow {
+            ControlFlow::Normal => self.addr += 1,
+            ControlFlow::JumpTo(line) => self.addr = *line,
+            _ => return false,
+        }
+        true
+    }
+
+    pub(crate) fn execute(&mut self,
+        expr: &Instruction,
+        thread_global: &mut VariableTable<ValueType>,
+        func_table: &VariableTable<AddrType>,
+        byte_code: &ByteCode
+    ) -> ControlFlow {
+        let mut control_flow = ControlFlow::Normal;
+        let result = expr.eval(thread_global, &mut self.stack, &mut self.var_table,
+            func_table, &mut control_flow);
+        match result {
+            Ok(()) => control_flow,
+            Err(Trap::UnsetVariable(slot)) => match byte_code.variable_name(slot) {
+                Some(name) => ControlFlow::Trap(format!("Variable '{}' is not set", name)),
+                None => ControlFlow::Trap(Trap::UnsetVariable(slot).to_string()),
+            },
+            Err(trap) => ControlFlow::Trap(trap.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunOutcome {
+    Finished,
+    // Threads are left but all of them are blocked
+    Deadlock,
+    StepLimit,
+}
+
+pub(crate) enum Step {
+    Ran,
+    Yielded,
+    Blocked,
+    Exited,
+}
+
+// Everything the threads share apart from the code and the globals
+pub(crate) struct Kernel {
+    pub(crate) main_thread_id: ThreadIdType,
+    next_thread_id: ThreadIdType,
+    pub(crate) end_addr: AddrType,
+    // Threads that are not being run at the moment
+    pub(crate) threads: BTreeMap<ThreadIdType, IThread>,
+    exits: BTreeMap<ThreadIdType, ThreadExit>,
+    // Address of the instruction each trapped thread stopped at
+    trap_addrs: BTreeMap<ThreadIdType, AddrType>,
+    names: BTreeMap<ThreadIdType, String>,
+    // Kills of threads that were running on another worker at the time
+    kills: BTreeSet<ThreadIdType>,
+    cancels: BTreeSet<ThreadIdType>,
+    // Mail for threads that were running on another worker at the time
+    mail: BTreeMap<ThreadIdType, VecDeque<ValueType>>,
+    // Channels told the id of a thread when it traps or is cancelled
+    monitors: BTreeMap<ThreadIdType, Vec<ValueType>>,
+    sync: SyncTable,
+    // Last value sent on each channel, receiving leaves it in place
+    channels: HashMap<ValueType, ValueType>,
+    pub(crate) scheduler: Box<dyn Scheduler>,
+    // Virtual time, one tick per executed instruction. Saturates, so that a
+    // sleep of any length can not overflow it
+    pub(crate) clock: ValueType,
+    pub(crate) step_limit: usize,
+    pub(crate) observer: Option<Box<dyn ExecutionObserver>>,
+}
+
+impl Kernel {
+    fn new(main_thread_id: ThreadIdType, start_addr: AddrType, end_addr: AddrType) -> Self {
+        let mut kernel = Kernel {
+            main_thread_id,
+            next_thread_id: main_thread_id + 1,
+            end_addr,
+            threads: Default::default(),
+            exits: Default::default(),
+            trap_addrs: Default::default(),
+            names: Default::default(),
+            kills: Default::default(),
+            cancels: Default::default(),
+            mail: Default::default(),
+            monitors: Default::default(),
+            sync: Default::default(),
+            channels: Default::default(),
+            scheduler: Box::new(RoundRobinScheduler::default()),
+            clock: 0,
+            step_limit: STEP_LIMIT,
+            observer: None,
+        };
+        kernel.threads.insert(main_thread_id, IThread::new(main_thread_id, start_addr));
+        kernel
+    }
+
+    pub(crate) fn runnable(&self) -> Vec<ThreadInfo> {
+        self.threads.values()
+            .filter(|th| !th.blocked && th.wake_at <= self.clock)
+            .map(|th| ThreadInfo { id: th.id, priority: th.priority })
+            .collect()
+    }
+
+    // Earliest deadline of the sleeping threads, None if every thread is blocked
+    pub(crate) fn next_wake(&self) -> Option<ValueType> {
+        self.threads.values()
+            .filter(|th| !th.blocked)
+            .map(|th| th.wake_at)
+            .min()
+    }
+
+    pub(crate) fn thread_label(&self, id: ThreadIdType) -> String {
+        match self.names.get(&id) {
+            Some(name) => format!("thread {} '{}'", id, name),
+            None => format!("thread {}", id),
+        }
+    }
+
+    pub(crate) fn finish(&mut self, thread: &mut IThread) -> Step {
+        let ret = thread.stack.pop();
+        self.exit(thread.id, ThreadExit::Returned(ret));
+        self.wake_threads();
+        Step::Exited
+    }
+
+    // Moves the mail sent while a worker was running the thread to its mailbox
+    pub(crate) fn deliver_mail(&mut self, thread: &mut IThread) {
+        if let Some(mail) = self.mail.remove(&thread.id) {
+            thread.mailbox.extend(mail);
+        }
+    }
+
+    // Whether the thread was killed while a worker was running it
+    pub(crate) fn take_kill(&mut self, thread: &IThread) -> bool {
+        if !self.kills.remove(&thread.id) {
+            return false;
+        }
+        self.exit(thread.id, ThreadExit::Cancelled);
+        self.wake_threads();
+        true
+    }
+
+    pub(crate) fn apply(&mut self, thread: &mut IThread, control_flow: ControlFlow) -> Step {
+        if thread.advance(&control_flow) {
+            return Step::Ran;
+        }
+
+        let mut yielded = false;
+        let mut cancelled = false;
+        let mut trap = None;
+        match control_flow {
+            ControlFlow::Normal | ControlFlow::JumpTo(_) => {},
+            ControlFlow::Block => thread.blocked = true,
+            ControlFlow::Spawn(addr, args) => {
+                thread.addr += 1;
+                let id = self.spawn_thread(thread, addr, args);
+                thread.stack.push(StackItem::Thread(id));
+            },
+            ControlFlow::SpawnN(addrs) => {
+                thread.addr += 1;
+                for addr in addrs {
+                    let id = self.spawn_thread(thread, addr, Vec::new());
+                    thread.stack.push(StackItem::Thread(id));
+                }
+            },
+            ControlFlow::Join(id) => {
+                match self.exits.get(&id) {
+                    // A thread that returned nothing joins as 0
+                    Some(ThreadExit::Returned(ret)) => {
+                        thread.addr += 1;
+                        thread.stack.push(ret.unwrap_or(StackItem::Value(0)));
+                    },
+                    Some(ThreadExit::Trapped(_)) => {
+                        thread.addr += 1;
+                        thread.stack.push(StackItem::Trapped);
+                    },
+                    Some(ThreadExit::Cancelled) => {
+                        thread.addr += 1;
+                        thread.stack.push(StackItem::Cancelled);
+                    },
+                    None if id != thread.id && self.is_alive(id) => {
+                        thread.stack.push(StackItem::Thread(id));
+                        thread.blocked = true;
+                    },
+                    None => trap = Some(format!("Thread {} can not be joined", id)),
+                }
+            },
+            ControlFlow::SetPriority(priority) => {
+                thread.addr += 1;
+                thread.priority = priority;
+            },
+            ControlFlow::Yield => {
+                thread.addr += 1;
+                yielded = true;
+            },
+            ControlFlow::Sleep(ticks) => {
+                thread.addr += 1;
+                thread.wake_at = self.clock.saturating_add(ticks);
+                yielded = true;
+            },
+            ControlFlow::Now => {
+                thread.addr += 1;
+                thread.stack.push(StackItem::Value(self.clock));
+            },
+            ControlFlow::ThreadId => {
+                thread.addr += 1;
+                thread.stack.push(StackItem::Value(thread.id as ValueType));
+            },
+            ControlFlow::ThreadCount => {
+                // Spawned and not exited yet, running ones included
+                thread.addr += 1;
+                let count = self.next_thread_id - self.exits.len();
+                thread.stack.push(StackItem::Value(count as ValueType));
+            },
+            ControlFlow::SetThreadName(name) => {
+                thread.addr += 1;
+                self.names.insert(thread.id, name);
+            },
+            ControlFlow::Kill(id) => {
+                thread.addr += 1;
+                if id == thread.id {
+                    cancelled = true;
+                } else if self.threads.remove(&id).is_some() {
+                    self.exit(id, ThreadExit::Cancelled);
+                } else if self.is_alive(id) {
+                    self.kills.insert(id);
+                }
+            },
+            ControlFlow::Cancel(id) => {
+                thread.addr += 1;
+                if self.is_alive(id) {
+                    self.cancels.insert(id);
+                }
+            },
+            ControlFlow::CheckCancel => {
+                thread.addr += 1;
+                cancelled = self.cancels.remove(&thread.id);
+            },
+            ControlFlow::Monitor(id, channel) => {
+                match self.exits.get(&id) {
+                    Some(ThreadExit::Returned(_)) => thread.addr += 1,
+                    // Already down, tell the channel right away
+                    Some(_) => {
+                        thread.addr += 1;
+                        self.channels.insert(channel, id as ValueType);
+                    },
+                    None if self.is_alive(id) => {
+                        thread.addr += 1;
+                        self.monitors.entry(id).or_default().push(channel);
+                    },
+                    None => trap = Some(format!("Thread {} can not be monitored", id)),
+                }
+            },
+            ControlFlow::Send(channel, value) => {
+                thread.addr += 1;
+                self.channels.insert(channel, value);
+            },
+            ControlFlow::Recv(channel) => {
+                let recv = self.channels.get(&channel).copied();
+                match recv {
+                    Some(value) => {
+                        thread.addr += 1;
+                        thread.stack.push(StackItem::Value(value));
+                    },
+                    None => {
+                        thread.stack.push(StackItem::Channel(channel));
+                        thread.blocked = true;
+                    },
+                }
+            },
+            ControlFlow::SendTo(id, value) => {
+                if id == thread.id {
+                    thread.addr += 1;
+                    thread.mailbox.push_back(value);
+                } else if let Some(target) = self.threads.get_mut(&id) {
+                    thread.addr += 1;
+                    target.mailbox.push_back(value);
+                } else if self.is_alive(id) {
+                    thread.addr += 1;
+                    self.mail.entry(id).or_default().push_back(value);
+                } else if id < self.next_thread_id {
+                    // Mail to an exited thread is dropped
+                    thread.addr += 1;
+                } else {
+                    trap = Some(format!("Thread {} can not be sent to", id));
+                }
+            },
+            ControlFlow::Receive => {
+                match thread.mailbox.pop_front() {
+                    Some(value) => {
+                        thread.addr += 1;
+                        thread.stack.push(StackItem::Value(value));
+                    },
+                    None => thread.blocked = true,
+                }
+            },
+            ControlFlow::Lock(name) => {
+                match self.sync.lock(&name, thread.id) {
+                    Ok(true) => thread.addr += 1,
+                    Ok(false) => thread.blocked = true,
+                    Err(msg) => trap = Some(msg),
+                }
+            },
+            ControlFlow::Unlock(name) => {
+                match self.sync.unlock(&name, thread.id) {
+                    Ok(()) => thread.addr += 1,
+                    Err(msg) => trap = Some(msg),
+                }
+            },
+            ControlFlow::Wait(name) => {
+                if !thread.waiting {
+                    // Releasing the mutex is progress for the others
+                    match self.sync.wait(&name, thread.id) {
+                        Ok(()) => {
+                            thread.waiting = true;
+                            yielded = true;
+                        },
+                        Err(msg) => trap = Some(msg),
+                    }
+                } else if self.sync.is_waiting(&name, thread.id) {
+                    thread.blocked = true;
+                } else {
+                    match self.sync.lock(&name, thread.id) {
+                        Ok(true) => {
+                            thread.waiting = false;
+                            thread.addr += 1;
+                        },
+                        Ok(false) => thread.blocked = true,
+                        Err(msg) => trap = Some(msg),
+                    }
+                }
+            },
+            ControlFlow::Notify(name) => {
+                match self.sync.notify(&name, thread.id) {
+                    Ok(()) => thread.addr += 1,
+                    Err(msg) => trap = Some(msg),
+                }
+            },
+            ControlFlow::NotifyAll(name) => {
+                match self.sync.notify_all(&name, thread.id) {
+                    Ok(()) => thread.addr += 1,
+                    Err(msg) => trap = Some(msg),
+                }
+            },
+            ControlFlow::Trap(msg) => trap = Some(msg),
+        };
+
+        if thread.blocked {
+            if let Some(observer) = &mut self.observer {
+                observer.on_block(thread.id);
+            }
+            return Step::Blocked;
+        }
+        self.wake_threads();
+        if let Some(msg) = trap {
+            self.trap_addrs.insert(thread.id, thread.addr);
+            self.exit(thread.id, ThreadExit::Trapped(msg));
+            Step::Exited
+        } else if cancelled {
+            self.exit(thread.id, ThreadExit::Cancelled);
+            Step::Exited
+        } else if yielded {
+            Step::Yielded
+        } else {
+            Step::Ran
+        }
+    }
+
+    fn is_alive(&self, id: ThreadIdType) -> bool {
+        id < self.next_thread_id && !self.exits.contains_key(&id)
+    }
+
+    fn exit(&mut self, id: ThreadIdType, exit: ThreadExit) {
+        let monitors = self.monitors.remove(&id).unwrap_or_default();
+        if !matches!(exit, ThreadExit::Returned(_)) {
+            for channel in monitors {
+                self.channels.insert(channel, id as ValueType);
+            }
+        }
+        self.cancels.remove(&id);
+        self.mail.remove(&id);
+        self.sync.release_thread(id);
+        if let Some(observer) = &mut self.observer {
+            observer.on_thread_exit(id, &exit);
+        }
+        self.exits.insert(id, exit);
+    }
+
+    fn wake_threads(&mut self) {
+        // Blocked threads retry their instruction after any thread event
+        for thread in self.threads.values_mut() {
+            thread.blocked = false;
+        }
+    }
+
+    // The child gets the priority of its parent
+    fn spawn_thread(&mut self, parent: &IThread, addr: AddrType, args: Vec<StackItem>) -> ThreadIdType {
+        let id = self.next_thread_id;
+        self.next_thread_id += 1;
+        let mut thread = IThread::new(id, addr);
+        thread.priority = parent.priority;
+        if let Some(observer) = &mut self.observer {
+            observer.on_spawn(parent.id, id);
+            thread.stack.journal = Some(Vec::new());
+        }
+        thread.stack.push(StackItem::ReturnAddr(self.end_addr));
+        for arg in args {
+            thread.stack.push(arg);
+        }
+        self.observe_stack(&mut thread);
+        self.threads.insert(id, thread);
+
+        id
+    }
+
+    // Tells the observer about the pushes and pops of the instruction the
+    // thread just ran, then about the instruction
+    pub(crate) fn observe_instruction(&mut self, thread: &mut IThread, addr: AddrType, expr: &Instruction) {
+        self.observe_stack(thread);
+        if let Some(observer) = &mut self.observer {
+            observer.on_instruction(thread.id, addr, expr);
+        }
+    }
+
+    // Tells the observer about the pushes and pops in the journal of the
+    // stack of the thread
+    pub(crate) fn observe_stack(&mut self, thread: &mut IThread) {
+        let (Some(observer), Some(journal)) = (&mut self.observer, thread.stack.journal.take()) else {
+            return;
+        };
+        for op in journal {
+            match op {
+                StackOp::Push(item) => observer.on_push(thread.id, &item),
+                StackOp::Pop(item) => observer.on_pop(thread.id, &item),
+            }
+        }
+    }
+}
+
+// The main thread and the threads there were, see Interpreter::restore
+pub(crate) struct Checkpoint {
+    items: Vec<StackItem>,
+    var_table: VariableSlots<ValueType>,
+    addr: AddrType,
+    next_thread_id: ThreadIdType,
 }
 
 pub struct Interpreter {
-    byte_code: ByteCode,
-    func_table: VariableTable<AddrType>,
-    main_thread_id: usize,
-    next_thread_id: usize,
-    threads: HashMap<usize, IThread>,
-    thread_global: VariableTable<ValueType>,
+    pub(crate) byte_code: ByteCode,
+    pub(crate) func_table: VariableTable<AddrType>,
+    pub(crate) thread_global: VariableTable<ValueType>,
+    pub(crate) kernel: Kernel,
 }
 
 impl Interpreter {
@@ -152,101 +776,201 @@ impl Interpreter {
         let main_thread_id = 0;
         let mut labels = byte_code.get_labels().clone();
         labels.write("_' end", byte_code.end_addr());
+        let kernel = Kernel::new(main_thread_id, byte_code.start_addr(), byte_code.end_addr());
 
-        let mut inter = Interpreter {
-            byte_code: byte_code,
+        Interpreter {
+            byte_code,
             func_table: labels,
-            main_thread_id: main_thread_id,
-            next_thread_id: main_thread_id + 1,
-            threads: Default::default(),
             thread_global: Default::default(),
-        };
+            kernel,
+        }
+    }
 
-        inter.threads.insert(inter.main_thread_id,
-            IThread::new(inter.main_thread_id, inter.byte_code.start_addr()));
+    pub fn set_scheduler(&mut self, scheduler: Box<dyn Scheduler>) {
+        self.kernel.scheduler = scheduler;
+    }
 
-        inter
+    pub fn set_step_limit(&mut self, step_limit: usize) {
+        self.kernel.step_limit = step_limit;
     }
 
-    pub fn run(&mut self) {
-        //let main_thread = self.threads.get_mut(&self.main_thread_id).unwrap();
+    // Replaces the observer, and the tracer. Parallel runs report to it too,
+    // but take the lock of the kernel after every instruction to do so
+    pub fn set_observer(&mut self, observer: Box<dyn ExecutionObserver>) {
+        self.kernel.observer = Some(observer);
+    }
 
-        let byte_code = &mut self.byte_code;
-        let mut control_flow = ControlFlow::Normal;
+    // Prints every instruction and the stack after it
+    pub fn set_trace(&mut self, trace: bool) {
+        self.kernel.observer = match trace {
+            true => Some(Box::new(Tracer::new(&self.byte_code))),
+            false => None,
+        };
+    }
+
+    pub fn run(&mut self) -> RunOutcome {
+        self.run_until(|_| false)
+    }
+
+    // Runs until pause holds between two steps, which counts as Finished
+    pub(crate) fn run_until(&mut self, pause: impl Fn(&Kernel) -> bool) -> RunOutcome {
         let mut i = 0;
 
-        let threads = &mut self.threads;
-        let mut thread_id = self.main_thread_id;
+        let mut current: Option<ThreadIdType> = None;
+        let mut slice_left = 0;
         loop {
+            if self.kernel.threads.is_empty() || pause(&self.kernel) {
+                return RunOutcome::Finished;
+            }
             i += 1;
-            if i == 1000 {
-                break;
+            if i > self.kernel.step_limit {
+                return RunOutcome::StepLimit;
             }
-            
-            // NEXT THREAD TO RUN
-            let mut thread;
-            loop {
-                let thread_opt = threads.get_mut(&thread_id);
-                
-                thread_id += 1;
-                if thread_id >= self.next_thread_id {
-                    thread_id = self.main_thread_id;
-                }
 
-                if let Some(th) = thread_opt {
-                    thread = th;
-                    break;
+            // NEXT THREAD TO RUN
+            let runnable = self.kernel.runnable();
+            if runnable.is_empty() {
+                match self.kernel.next_wake() {
+                    // Only sleeping threads left, skip ahead to the first deadline
+                    Some(wake_at) => {
+                        self.kernel.clock = wake_at;
+                        continue;
+                    },
+                    // Every thread is blocked, nothing can wake them up
+                    None => return RunOutcome::Deadlock,
                 }
             }
-            
+            let thread_id = match current {
+                Some(id) if slice_left > 0 && runnable.iter().any(|th| th.id == id) => id,
+                _ => {
+                    slice_left = self.kernel.scheduler.time_slice().max(1);
+                    self.kernel.scheduler.next(&runnable)
+                },
+            };
+            slice_left -= 1;
+            current = Some(thread_id);
+
             // RUN ONE INSTRUCTION FROM THE THREAD
-            let mut end_thread = false;
-            {
-                let expr_next = byte_code.get_line_mut(thread.addr);
-                match expr_next {
-                    Some(expr) => {
-                        println!("{}", expr.name());
-                        expr.eval(&mut self.thread_global,
-                            &mut thread.stack, &mut thread.var_table,
-                            &mut self.func_table, &mut control_flow);
+            let step = self.step(thread_id);
+            self.kernel.clock = self.kernel.clock.saturating_add(1);
+            if !matches!(step, Step::Ran) {
+                current = None;
+            }
+        }
+    }
 
-                    },
-                    None => {
-                        end_thread = true;
-                    },
+    // Swaps in code that starts with the current code, or the code it
+    // replaced, the threads go on where they are. Returns the code swapped out
+    pub(crate) fn reload(&mut self, byte_code: ByteCode) -> ByteCode {
+        let (old_end, end) = (self.kernel.end_addr, byte_code.end_addr());
+        // Spawned threads return to the end to exit
+        for thread in self.kernel.threads.values_mut() {
+            if thread.id == self.kernel.main_thread_id {
+                continue;
+            }
+            if let Some(bottom) = thread.stack.items.first_mut() {
+                if *bottom == StackItem::ReturnAddr(old_end) {
+                    *bottom = StackItem::ReturnAddr(end);
                 }
             }
+        }
+        self.kernel.end_addr = end;
+        self.func_table = byte_code.get_labels().clone();
+        self.func_table.write("_' end", end);
+        std::mem::replace(&mut self.byte_code, byte_code)
+    }
 
-            // CONTROL FLOW THE THREAD
-            match control_flow {
-                ControlFlow::Normal => thread.addr += 1,
-                ControlFlow::Block => {},
-                ControlFlow::JumpTo(line) => thread.addr = line,
-                ControlFlow::Spawn(f1, f2) => {
-                    thread.addr += 1;
+    pub(crate) fn checkpoint(&self) -> Checkpoint {
+        let main = &self.kernel.threads[&self.kernel.main_thread_id];
+        Checkpoint {
+            items: main.stack.items.clone(),
+            var_table: main.var_table.clone(),
+            addr: main.addr,
+            next_thread_id: self.kernel.next_thread_id,
+        }
+    }
 
-                    let end_addr = self.func_table.read("_' end").unwrap();
+    // Puts the main thread back as it was, also after it exited, and cancels
+    // the threads started since. Globals and channels stay as they are
+    pub(crate) fn restore(&mut self, checkpoint: Checkpoint) {
+        let kernel = &mut self.kernel;
+        let started: Vec<ThreadIdType> = kernel.threads.keys()
+                .copied()
+                .filter(|id| *id >= checkpoint.next_thread_id)
+                .collect();
+        for id in started {
+            kernel.threads.remove(&id);
+            kernel.exit(id, ThreadExit::Cancelled);
+        }
 
-                    let mut id = self.next_thread_id;
-                    self.next_thread_id += 1;
-                    let mut th1 = IThread::new(id, f1);
-                    th1.stack.push(StackItem::ReturnAddr(end_addr));
-                    threads.insert(id, th1);
+        let id = kernel.main_thread_id;
+        kernel.exits.remove(&id);
+        kernel.trap_addrs.remove(&id);
+        let main = kernel.threads.entry(id).or_insert_with(|| IThread::new(id, checkpoint.addr));
+        main.stack.items = checkpoint.items;
+        main.var_table = checkpoint.var_table;
+        main.addr = checkpoint.addr;
+        main.blocked = false;
+        kernel.wake_threads();
+    }
 
-                    id = self.next_thread_id;
-                    self.next_thread_id += 1;
-                    let mut th2 = IThread::new(id, f2);
-                    th2.stack.push(StackItem::ReturnAddr(end_addr));
-                    threads.insert(id, th2);
-                },
-            };
-            control_flow = ControlFlow::Normal;
+    pub fn main_thread_id(&self) -> ThreadIdType {
+        self.kernel.main_thread_id
+    }
 
-            if end_thread {
-                threads.remove(&thread_id);
-            }
+    pub fn thread_exit(&self, id: ThreadIdType) -> Option<&ThreadExit> {
+        self.kernel.exits.get(&id)
+    }
 
-            // NEXT ITER (PROBABLY WITH THE NEXT THREAD)
+    pub fn thread_exits(&self) -> &BTreeMap<ThreadIdType, ThreadExit> {
+        &self.kernel.exits
+    }
+
+    // file:line:column of the instruction the thread trapped at
+    pub fn trap_location(&self, id: ThreadIdType) -> Option<String> {
+        self.byte_code.location(*self.kernel.trap_addrs.get(&id)?)
+    }
+
+    pub fn thread_name(&self, id: ThreadIdType) -> Option<&str> {
+        self.kernel.names.get(&id).map(|name| name.as_str())
+    }
+
+    pub fn thread_label(&self, id: ThreadIdType) -> String {
+        self.kernel.thread_label(id)
+    }
+
+    pub fn live_threads(&self) -> Vec<ThreadIdType> {
+        self.kernel.threads.keys().copied().collect()
+    }
+
+    fn step(&mut self, thread_id: ThreadIdType) -> Step {
+        let mut thread = self.kernel.threads.remove(&thread_id).unwrap();
+
+        let step = match self.byte_code.get_line(thread.addr) {
+            Some(expr) if self.kernel.observer.is_some() => {
+                let addr = thread.addr;
+                thread.stack.journal = Some(Vec::new());
+                let control_flow = thread.execute(expr,
+                    &mut self.thread_global, &self.func_table, &self.byte_code);
+                self.kernel.observe_instruction(&mut thread, addr, expr);
+
+                // The kernel pushes the results of some instructions itself
+                thread.stack.journal = Some(Vec::new());
+                let step = self.kernel.apply(&mut thread, control_flow);
+                self.kernel.observe_stack(&mut thread);
+                step
+            },
+            Some(expr) => {
+                let control_flow = thread.execute(expr,
+                    &mut self.thread_global, &self.func_table, &self.byte_code);
+                self.kernel.apply(&mut thread, control_flow)
+            },
+            None => self.kernel.finish(&mut thread),
+        };
+
+        if !matches!(step, Step::Exited) {
+            self.kernel.threads.insert(thread_id, thread);
         }
+        step
     }
-}
\ No newline at end of file
+}
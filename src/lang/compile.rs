@@ -0,0 +1,396 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::bytecode::{ByteCode, SourcePos};
+use crate::instruction::Instruction;
+use crate::interpreter::{AddrType, SlotType, VariableTable};
+use crate::lang::CompileError;
+use crate::lang::lexer::{self, Pos};
+use crate::lang::parser::{BinaryOp, Expression, Function, Parser, Statement, StatementKind};
+use crate::verify;
+
+
+impl ByteCode {
+    // Compiles a program of the high level language, see lang
+    pub fn compile(source: &str) -> Result<Self, CompileError> {
+        let program = Parser::new(lexer::tokenize(source)?).program()?;
+
+        let mut compiler = Compiler::default();
+        for function in &program.functions {
+            if compiler.functions.insert(function.name.clone(), function.params.clone()).is_some() {
+                return Err(function.pos.error(format!("function {} is defined twice", function.name)));
+            }
+        }
+        let mut spawned = BTreeSet::new();
+        for function in &program.functions {
+            spawns(&function.body, &mut spawned);
+        }
+        spawns(&program.main, &mut spawned);
+
+        for function in &program.functions {
+            compiler.function(function, spawned.contains(&function.name))?;
+        }
+        compiler.pos = Pos { line: 1, column: 1 };
+        let start_addr = compiler.exprs.len();
+        compiler.emit(Instruction::Start);
+        compiler.scope = String::new();
+        compiler.declared = BTreeSet::new();
+        compiler.block(&program.main)?;
+        let end = compiler.entry(MAIN_END);
+        compiler.place(end);
+        compiler.recursion()?;
+
+        for (addr, target) in std::mem::take(&mut compiler.fixups) {
+            let line = compiler.targets[target].unwrap();
+            compiler.exprs[addr].set_target(line);
+        }
+        // Compiled code that does not verify is a bug of the compiler
+        if let Some((addr, message)) = verify::verify(&compiler.exprs, start_addr).into_iter().next() {
+            return Err(compiler.positions[addr].error(format!("compiles to bad byte code: {}", message)));
+        }
+
+        let mut labels = VariableTable::default();
+        for (name, target) in &compiler.entries {
+            if let Some(addr) = compiler.targets[*target] {
+                if name != MAIN_END {
+                    labels.write(name, addr);
+                }
+            }
+        }
+        Ok(ByteCode {
+            labels,
+            variables: compiler.variables,
+            exprs: compiler.exprs,
+            start_addr,
+            warnings: Vec::new(),
+            file: None,
+            positions: compiler.positions.iter()
+                    .map(|pos| SourcePos { line: pos.line, column: pos.column })
+                    .collect(),
+        })
+    }
+}
+
+// Target the main thread jumps to when it returns
+const MAIN_END: &str = "_' end";
+
+// Names of the functions started with spawn
+fn spawns(statements: &[Statement], spawned: &mut BTreeSet<String>) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Spawn(name, _) => { spawned.insert(name.clone()); },
+            StatementKind::If(_, then, otherwise) => {
+                spawns(then, spawned);
+                spawns(otherwise, spawned);
+            },
+            StatementKind::While(_, body) | StatementKind::Repeat(_, body) => spawns(body, spawned),
+            _ => {},
+        }
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    // Parameters by function name
+    functions: HashMap<String, Vec<String>>,
+    exprs: Vec<Instruction>,
+    // Statement each instruction was compiled from
+    positions: Vec<Pos>,
+    pos: Pos,
+    // Addresses of jump targets, known once the code before them is
+    targets: Vec<Option<AddrType>>,
+    // Instructions to point at a target once every address is known
+    fixups: Vec<(AddrType, usize)>,
+    // Targets of the functions by name, a spawned function has a second one
+    // named {function}.spawn
+    entries: HashMap<String, usize>,
+    variables: Vec<String>,
+    // Function being compiled, empty for the main thread
+    scope: String,
+    declared: BTreeSet<String>,
+    repeats: usize,
+    // Caller, callee and where the call is
+    calls: Vec<(String, String, Pos)>,
+}
+
+impl Compiler {
+    fn emit(&mut self, expr: Instruction) {
+        self.exprs.push(expr);
+        self.positions.push(self.pos);
+    }
+
+    fn target(&mut self) -> usize {
+        self.targets.push(None);
+        self.targets.len() - 1
+    }
+
+    fn entry(&mut self, name: &str) -> usize {
+        match self.entries.get(name) {
+            Some(target) => *target,
+            None => {
+                let target = self.target();
+                self.entries.insert(String::from(name), target);
+                target
+            },
+        }
+    }
+
+    // The next instruction is where target points
+    fn place(&mut self, target: usize) {
+        self.targets[target] = Some(self.exprs.len());
+    }
+
+    fn emit_to(&mut self, expr: Instruction, target: usize) {
+        self.fixups.push((self.exprs.len(), target));
+        self.emit(expr);
+    }
+
+    // Variables of a function are named {function}.{variable}
+    fn slot(&mut self, name: &str) -> SlotType {
+        let name = match self.scope.is_empty() || name.starts_with("_'") {
+            true => String::from(name),
+            false => format!("{}.{}", self.scope, name),
+        };
+        self.global_slot(name)
+    }
+
+    fn global_slot(&mut self, name: String) -> SlotType {
+        match self.variables.iter().position(|variable| *variable == name) {
+            Some(slot) => slot,
+            None => {
+                self.variables.push(name);
+                self.variables.len() - 1
+            },
+        }
+    }
+
+    fn variable(&mut self, name: &str, pos: Pos) -> Result<SlotType, CompileError> {
+        if !self.declared.contains(name) {
+            return Err(pos.error(format!("variable {} is not defined", name)));
+        }
+        Ok(self.slot(name))
+    }
+
+    fn arguments(&mut self, name: &str, args: &[Expression], pos: Pos) -> Result<(), CompileError> {
+        match self.functions.get(name) {
+            None => return Err(pos.error(format!("function {} is not defined", name))),
+            Some(params) if params.len() != args.len() =>
+                return Err(pos.error(format!("function {} takes {} arguments but is given {}",
+                    name, params.len(), args.len()))),
+            _ => {},
+        }
+        for arg in args {
+            self.expression(arg)?;
+        }
+        Ok(())
+    }
+
+    // A called function finds its arguments in its parameters, a spawned one
+    // on its stack above the return address
+    fn function(&mut self, function: &Function, spawned: bool) -> Result<(), CompileError> {
+        self.pos = function.pos;
+        self.scope = function.name.clone();
+        self.declared = function.params.iter().cloned().collect();
+        if spawned {
+            let target = self.entry(&format!("{}.spawn", function.name));
+            self.place(target);
+            for param in function.params.iter().rev() {
+                let slot = self.slot(param);
+                self.emit(Instruction::WriteVar(slot));
+            }
+        }
+        let target = self.entry(&function.name);
+        self.place(target);
+        self.block(&function.body)?;
+        self.emit(Instruction::LoadVal(0));
+        self.emit(Instruction::ReturnValue);
+        Ok(())
+    }
+
+    // Variables defined in a block are gone after it
+    fn block(&mut self, statements: &[Statement]) -> Result<(), CompileError> {
+        let declared = self.declared.clone();
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        self.declared = declared;
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        self.pos = statement.pos;
+        match &statement.kind {
+            StatementKind::Let(name, val) => {
+                self.expression(val)?;
+                self.declared.insert(name.clone());
+                let slot = self.slot(name);
+                self.emit(Instruction::WriteVar(slot));
+            },
+            StatementKind::Assign(name, val) => {
+                let slot = self.variable(name, statement.pos)?;
+                self.expression(val)?;
+                self.emit(Instruction::WriteVar(slot));
+            },
+            // JUMP_ZERO leaves the condition on the stack for both branches
+            StatementKind::If(condition, then, otherwise) => {
+                let (otherwise_target, end) = (self.target(), self.target());
+                self.expression(condition)?;
+                self.emit_to(Instruction::JumpZero(0), otherwise_target);
+                self.emit(Instruction::Pop);
+                self.block(then)?;
+                self.emit_to(Instruction::Jump(0), end);
+                self.place(otherwise_target);
+                self.emit(Instruction::Pop);
+                self.block(otherwise)?;
+                self.place(end);
+            },
+            StatementKind::While(condition, body) => {
+                let (start, end) = (self.target(), self.target());
+                self.place(start);
+                self.expression(condition)?;
+                self.emit_to(Instruction::JumpZero(0), end);
+                self.emit(Instruction::Pop);
+                self.block(body)?;
+                self.pos = statement.pos;
+                self.emit_to(Instruction::Jump(0), start);
+                self.place(end);
+                self.emit(Instruction::Pop);
+            },
+            StatementKind::Repeat(count, body) => {
+                self.expression(count)?;
+                let loopstart = self.exprs.len();
+                let loop_var = self.slot(&format!("_' i{}", loopstart));
+                let count_var = self.slot(&format!("_' n{}", loopstart));
+                self.emit(Instruction::Loop { loop_var, count_var, endloop: 0 });
+                self.repeats += 1;
+                self.block(body)?;
+                self.repeats -= 1;
+                self.pos = statement.pos;
+                let endloop = self.exprs.len();
+                self.emit(Instruction::EndLoop { loop_var, loopstart });
+                self.exprs[loopstart].set_target(endloop);
+            },
+            StatementKind::Return(val) => {
+                match val {
+                    Some(val) => self.expression(val)?,
+                    None if self.scope.is_empty() => {},
+                    None => self.emit(Instruction::LoadVal(0)),
+                }
+                if self.scope.is_empty() {
+                    let end = self.entry(MAIN_END);
+                    self.emit_to(Instruction::Jump(0), end);
+                } else if self.repeats > 0 {
+                    // LOOP would still count when the function is called again
+                    return Err(statement.pos.error(String::from("return inside repeat")));
+                } else {
+                    self.emit(Instruction::ReturnValue);
+                }
+            },
+            StatementKind::Spawn(name, args) => {
+                self.arguments(name, args, statement.pos)?;
+                let target = self.entry(&format!("{}.spawn", name));
+                self.emit_to(Instruction::LoadAddr(0), target);
+                self.emit(Instruction::Spawn(args.len()));
+                // Thread handles can not be kept in variables
+                self.emit(Instruction::Pop);
+            },
+            StatementKind::Send(channel, val) => {
+                self.expression(val)?;
+                self.emit(Instruction::LoadChannel(*channel));
+                self.emit(Instruction::SendChannel);
+            },
+            StatementKind::Expression(val) => {
+                self.expression(val)?;
+                self.emit(Instruction::Pop);
+            },
+        }
+        Ok(())
+    }
+
+    // A call to a function that is still running in the same thread would
+    // overwrite its variables
+    fn recursion(&self) -> Result<(), CompileError> {
+        let calls = |caller: &str| -> Vec<&str> {
+            self.calls.iter()
+                    .filter(|(from, _, _)| from == caller)
+                    .map(|(_, to, _)| to.as_str())
+                    .collect()
+        };
+        for (caller, callee, pos) in self.calls.iter().filter(|(from, _, _)| !from.is_empty()) {
+            let mut seen = BTreeSet::new();
+            let mut work = vec![callee.as_str()];
+            while let Some(function) = work.pop() {
+                if function == caller {
+                    return Err(match caller == callee {
+                        true => pos.error(format!("function {} can not call itself", caller)),
+                        false => pos.error(format!("function {} can not call {}, which calls {} again",
+                            caller, callee, caller)),
+                    });
+                }
+                if seen.insert(function) {
+                    work.extend(calls(function));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Leaves 1 on the stack for a 0 on top and 0 for anything else
+    fn not(&mut self) {
+        let (zero, end) = (self.target(), self.target());
+        self.emit_to(Instruction::JumpZero(0), zero);
+        self.emit(Instruction::Pop);
+        self.emit(Instruction::LoadVal(0));
+        self.emit_to(Instruction::Jump(0), end);
+        self.place(zero);
+        self.emit(Instruction::Pop);
+        self.emit(Instruction::LoadVal(1));
+        self.place(end);
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Result<(), CompileError> {
+        match expression {
+            Expression::Int(val) => self.emit(Instruction::LoadVal(*val)),
+            Expression::Var(name, pos) => {
+                let slot = self.variable(name, *pos)?;
+                self.emit(Instruction::ReadVar(slot));
+            },
+            Expression::Neg(val) => {
+                self.emit(Instruction::LoadVal(0));
+                self.expression(val)?;
+                self.emit(Instruction::Subtract);
+            },
+            Expression::Binary(op, left, right) => {
+                self.expression(left)?;
+                self.expression(right)?;
+                match op {
+                    BinaryOp::Add => self.emit(Instruction::Add),
+                    BinaryOp::Mul => self.emit(Instruction::Multiply),
+                    BinaryOp::Sub => self.emit(Instruction::Subtract),
+                    BinaryOp::Eq => self.emit(Instruction::Equal),
+                    BinaryOp::Ne => {
+                        self.emit(Instruction::Equal);
+                        self.not();
+                    },
+                }
+            },
+            // Parameters are written right to left, the stack has the last
+            // argument on top
+            Expression::Call(name, args, pos) => {
+                self.arguments(name, args, *pos)?;
+                self.calls.push((self.scope.clone(), name.clone(), *pos));
+                for param in self.functions[name].clone().iter().rev() {
+                    let slot = self.global_slot(format!("{}.{}", name, param));
+                    self.emit(Instruction::WriteVar(slot));
+                }
+                let target = self.entry(name);
+                let return_line = self.exprs.len() + 1;
+                self.emit_to(Instruction::Call { func_line: 0, return_line }, target);
+            },
+            Expression::Recv(channel) => {
+                self.emit(Instruction::LoadChannel(*channel));
+                self.emit(Instruction::RecvChannel);
+            },
+        }
+        Ok(())
+    }
+}
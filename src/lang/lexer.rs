@@ -0,0 +1,74 @@
+use crate::interpreter::ValueType;
+use crate::lang::CompileError;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Int(ValueType),
+    // Names and keywords
+    Ident(String),
+    Symbol(&'static str),
+    End,
+}
+
+// Position in the source, both counting from 1
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pos {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Pos {
+    pub fn error(self, message: String) -> CompileError {
+        CompileError { line: self.line, column: self.column, message }
+    }
+}
+
+// Longest first, so == is not read as two =
+const SYMBOLS: [&str; 12] = ["==", "!=", "+", "-", "*", "=", "(", ")", "{", "}", ",", ";"];
+
+// Splits the source into tokens, // starts a comment that runs to the end
+// of the line
+pub fn tokenize(source: &str) -> Result<Vec<(Token, Pos)>, CompileError> {
+    let mut tokens = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line = line.split("//").next().unwrap();
+        let chars: Vec<char> = line.chars().collect();
+        let mut column = 0;
+        while column < chars.len() {
+            let pos = Pos { line: i + 1, column: column + 1 };
+            let c = chars[column];
+            if c.is_whitespace() {
+                column += 1;
+            } else if c.is_ascii_digit() {
+                let start = column;
+                while column < chars.len() && chars[column].is_ascii_digit() {
+                    column += 1;
+                }
+                let digits: String = chars[start..column].iter().collect();
+                let val = digits.parse()
+                        .map_err(|_| pos.error(format!("{} does not fit in a value", digits)))?;
+                tokens.push((Token::Int(val), pos));
+            } else if c.is_alphabetic() || c == '_' {
+                let start = column;
+                while column < chars.len() && (chars[column].is_alphanumeric() || chars[column] == '_') {
+                    column += 1;
+                }
+                tokens.push((Token::Ident(chars[start..column].iter().collect()), pos));
+            } else {
+                let rest: String = chars[column..].iter().collect();
+                match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+                    Some(symbol) => {
+                        column += symbol.len();
+                        tokens.push((Token::Symbol(symbol), pos));
+                    },
+                    None => return Err(pos.error(format!("unexpected character '{}'", c))),
+                }
+            }
+        }
+    }
+    let last = source.lines().last().unwrap_or("");
+    let end = Pos { line: source.lines().count().max(1), column: last.chars().count() + 1 };
+    tokens.push((Token::End, end));
+    Ok(tokens)
+}
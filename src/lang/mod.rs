@@ -0,0 +1,35 @@
+// A small language with infix expressions that compiles to byte code:
+//
+//     fn square(x) { return x * x; }
+//     let n = 3;
+//     spawn worker(n);
+//     if n == 3 { send(0, square(n)); } else { send(0, 0); }
+//     while n != 0 { n = n - 1; }
+//     repeat 2 { n = n + square(2); }
+//     return recv(0) + n;
+//
+// Values are the integers of the byte code, 0 is false and everything else
+// true. The statements outside of functions run on the main thread.
+// Functions keep their variables in the thread, like the byte code does, so
+// a function can not call itself, not even through other functions. Threads
+// started with spawn get variables of their own and talk over channels
+pub mod lexer;
+pub mod parser;
+pub mod compile;
+
+use std::fmt;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    // Position in the source, both counting from 1
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
@@ -0,0 +1,354 @@
+use crate::interpreter::ValueType;
+use crate::lang::CompileError;
+use crate::lang::lexer::{Pos, Token};
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Expression {
+    Int(ValueType),
+    Var(String, Pos),
+    Neg(Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+    Call(String, Vec<Expression>, Pos),
+    // Receive from a channel
+    Recv(ValueType),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StatementKind {
+    Let(String, Expression),
+    Assign(String, Expression),
+    If(Expression, Vec<Statement>, Vec<Statement>),
+    While(Expression, Vec<Statement>),
+    // Runs the body a number of times, with LOOP
+    Repeat(Expression, Vec<Statement>),
+    Return(Option<Expression>),
+    Spawn(String, Vec<Expression>),
+    Send(ValueType, Expression),
+    Expression(Expression),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub pos: Pos,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Statement>,
+    pub pos: Pos,
+}
+
+// Functions, and the statements outside of them that make up the main thread
+#[derive(Debug, Default, PartialEq)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub main: Vec<Statement>,
+}
+
+const KEYWORDS: [&str; 10] =
+    ["fn", "let", "if", "else", "while", "repeat", "return", "spawn", "send", "recv"];
+
+pub struct Parser {
+    tokens: Vec<(Token, Pos)>,
+    next: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<(Token, Pos)>) -> Self {
+        Parser { tokens, next: 0 }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].0
+    }
+
+    fn pos(&self) -> Pos {
+        self.tokens[self.next].1
+    }
+
+    fn advance(&mut self) -> (Token, Pos) {
+        let token = self.tokens[self.next].clone();
+        if token.0 != Token::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn describe(token: &Token) -> String {
+        match token {
+            Token::Int(val) => format!("{}", val),
+            Token::Ident(name) => format!("'{}'", name),
+            Token::Symbol(symbol) => format!("'{}'", symbol),
+            Token::End => String::from("the end of the source"),
+        }
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, CompileError> {
+        Err(self.pos().error(format!("expected {} but found {}", expected, Self::describe(self.peek()))))
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Token::Symbol(s) if *s == symbol)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Ident(name) if name == keyword)
+    }
+
+    fn symbol(&mut self, symbol: &str) -> Result<(), CompileError> {
+        if !self.is_symbol(symbol) {
+            return self.unexpected(&format!("'{}'", symbol));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<(String, Pos), CompileError> {
+        match self.peek() {
+            Token::Ident(name) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                let (_, pos) = self.advance();
+                Ok((name, pos))
+            },
+            _ => self.unexpected("a name"),
+        }
+    }
+
+    fn int(&mut self) -> Result<ValueType, CompileError> {
+        match *self.peek() {
+            Token::Int(val) => {
+                self.advance();
+                Ok(val)
+            },
+            _ => self.unexpected("a number"),
+        }
+    }
+
+    pub fn program(&mut self) -> Result<Program, CompileError> {
+        let mut program = Program::default();
+        while *self.peek() != Token::End {
+            if self.is_keyword("fn") {
+                program.functions.push(self.function()?);
+            } else {
+                program.main.push(self.statement()?);
+            }
+        }
+        Ok(program)
+    }
+
+    fn function(&mut self) -> Result<Function, CompileError> {
+        let (_, pos) = self.advance();
+        let (name, _) = self.name()?;
+        self.symbol("(")?;
+        let mut params = Vec::new();
+        while !self.is_symbol(")") {
+            if !params.is_empty() {
+                self.symbol(",")?;
+            }
+            params.push(self.name()?.0);
+        }
+        self.advance();
+        let body = self.block()?;
+        Ok(Function { name, params, body, pos })
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, CompileError> {
+        self.symbol("{")?;
+        let mut statements = Vec::new();
+        while !self.is_symbol("}") {
+            if *self.peek() == Token::End {
+                return self.unexpected("'}'");
+            }
+            statements.push(self.statement()?);
+        }
+        self.advance();
+        Ok(statements)
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Expression>, CompileError> {
+        self.symbol("(")?;
+        let mut args = Vec::new();
+        while !self.is_symbol(")") {
+            if !args.is_empty() {
+                self.symbol(",")?;
+            }
+            args.push(self.expression()?);
+        }
+        self.advance();
+        Ok(args)
+    }
+
+    fn statement(&mut self) -> Result<Statement, CompileError> {
+        let pos = self.pos();
+        let keyword = match self.peek() {
+            Token::Ident(name) => name.clone(),
+            _ => String::new(),
+        };
+        let kind = match keyword.as_str() {
+            "let" => {
+                self.advance();
+                let (name, _) = self.name()?;
+                self.symbol("=")?;
+                let val = self.expression()?;
+                self.symbol(";")?;
+                StatementKind::Let(name, val)
+            },
+            "if" => {
+                self.advance();
+                let condition = self.expression()?;
+                let then = self.block()?;
+                let otherwise = match self.is_keyword("else") {
+                    true => {
+                        self.advance();
+                        match self.is_keyword("if") {
+                            true => vec![self.statement()?],
+                            false => self.block()?,
+                        }
+                    },
+                    false => Vec::new(),
+                };
+                StatementKind::If(condition, then, otherwise)
+            },
+            "while" => {
+                self.advance();
+                let condition = self.expression()?;
+                StatementKind::While(condition, self.block()?)
+            },
+            "repeat" => {
+                self.advance();
+                let count = self.expression()?;
+                StatementKind::Repeat(count, self.block()?)
+            },
+            "return" => {
+                self.advance();
+                let val = match self.is_symbol(";") {
+                    true => None,
+                    false => Some(self.expression()?),
+                };
+                self.symbol(";")?;
+                StatementKind::Return(val)
+            },
+            "spawn" => {
+                self.advance();
+                let (name, _) = self.name()?;
+                let args = self.arguments()?;
+                self.symbol(";")?;
+                StatementKind::Spawn(name, args)
+            },
+            "send" => {
+                self.advance();
+                self.symbol("(")?;
+                let channel = self.int()?;
+                self.symbol(",")?;
+                let val = self.expression()?;
+                self.symbol(")")?;
+                self.symbol(";")?;
+                StatementKind::Send(channel, val)
+            },
+            _ => {
+                let is_assign = matches!(self.tokens.get(self.next + 1), Some((Token::Symbol("="), _)));
+                if is_assign {
+                    let (name, _) = self.name()?;
+                    self.advance();
+                    let val = self.expression()?;
+                    self.symbol(";")?;
+                    StatementKind::Assign(name, val)
+                } else {
+                    let val = self.expression()?;
+                    self.symbol(";")?;
+                    StatementKind::Expression(val)
+                }
+            },
+        };
+        Ok(Statement { kind, pos })
+    }
+
+    pub fn expression(&mut self) -> Result<Expression, CompileError> {
+        let mut left = self.additive()?;
+        loop {
+            let op = match self.peek() {
+                Token::Symbol("==") => BinaryOp::Eq,
+                Token::Symbol("!=") => BinaryOp::Ne,
+                _ => return Ok(left),
+            };
+            self.advance();
+            left = Expression::Binary(op, Box::new(left), Box::new(self.additive()?));
+        }
+    }
+
+    fn additive(&mut self) -> Result<Expression, CompileError> {
+        let mut left = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Token::Symbol("+") => BinaryOp::Add,
+                Token::Symbol("-") => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.advance();
+            left = Expression::Binary(op, Box::new(left), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expression, CompileError> {
+        let mut left = self.unary()?;
+        while self.is_symbol("*") {
+            self.advance();
+            left = Expression::Binary(BinaryOp::Mul, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, CompileError> {
+        if !self.is_symbol("-") {
+            return self.primary();
+        }
+        self.advance();
+        Ok(match self.unary()? {
+            Expression::Int(val) => Expression::Int(-val),
+            val => Expression::Neg(Box::new(val)),
+        })
+    }
+
+    fn primary(&mut self) -> Result<Expression, CompileError> {
+        match self.peek().clone() {
+            Token::Int(val) => {
+                self.advance();
+                Ok(Expression::Int(val))
+            },
+            Token::Symbol("(") => {
+                self.advance();
+                let val = self.expression()?;
+                self.symbol(")")?;
+                Ok(val)
+            },
+            Token::Ident(name) if name == "recv" => {
+                self.advance();
+                self.symbol("(")?;
+                let channel = self.int()?;
+                self.symbol(")")?;
+                Ok(Expression::Recv(channel))
+            },
+            Token::Ident(_) => {
+                let (name, pos) = self.name()?;
+                match self.is_symbol("(") {
+                    true => Ok(Expression::Call(name, self.arguments()?, pos)),
+                    false => Ok(Expression::Var(name, pos)),
+                }
+            },
+            _ => self.unexpected("an expression"),
+        }
+    }
+}
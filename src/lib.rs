@@ -1,13 +1,39 @@
 
 pub mod expr;
+pub mod instruction;
 pub mod parse;
 pub mod bytecode;
+pub mod binary;
+pub mod disasm;
+pub mod verify;
+pub mod optimize;
+pub mod cfg;
+pub mod dot;
 pub mod interpreter;
+pub mod observer;
+pub mod scheduler;
+pub mod explore;
+pub mod sync;
+pub mod parallel;
+pub mod repl;
+pub mod lang;
 
 
 #[cfg(test)]
 mod tests {
-    use crate::{interpreter::Interpreter, bytecode::ByteCode};
+    use crate::{interpreter::{Interpreter, RunOutcome, StackItem, ThreadExit}, bytecode::{ByteCode, SourcePos}};
+    use crate::scheduler::{PriorityScheduler, RoundRobinScheduler, Scheduler, ThreadInfo};
+    use crate::explore::{Explorer, Failure, Schedule};
+    use crate::expr::Expr;
+    use crate::interpreter::{AddrType, ControlFlow, Stack, Trap, ValueType, VariableSlots, VariableTable};
+    use crate::parse::ParseEngine;
+    use crate::binary::FormatError;
+    use crate::instruction::Instruction;
+    use crate::scheduler::Rng;
+    use crate::repl::Repl;
+    use crate::observer::ExecutionObserver;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
 
     #[test]
     fn it_works() {
@@ -17,4 +43,1126 @@ mod tests {
 
         interpreter.run();
     }
+
+    #[test]
+    fn join_returns_thread_results() {
+        let byte_code = ByteCode::from_source("
+            LABEL 'square'
+            WRITE_VAR 'x'
+            READ_VAR 'x'
+            READ_VAR 'x'
+            MULTIPLY
+            RETURN_VALUE
+
+            LABEL 'fail'
+            ADD
+
+            START
+            LOAD_ADDR 'fail'
+            SPAWN
+            LOAD_VAL 7
+            LOAD_ADDR 'square'
+            SPAWN 1
+            JOIN
+            WRITE_VAR 'square'
+            JOIN
+            READ_VAR 'square'
+        ");
+        let mut interpreter = Interpreter::new(byte_code);
+        interpreter.run();
+
+        assert!(matches!(interpreter.thread_exit(1), Some(ThreadExit::Trapped(_))));
+        assert_eq!(interpreter.thread_exit(interpreter.main_thread_id()),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(49)))));
+    }
+
+    const PRIORITY_SRC: &str = "
+        LABEL 'worker'
+        WRITE_VAR 'v'
+        READ_VAR 'v'
+        SET_PRIORITY
+        READ_VAR 'v'
+        LOAD_CHANNEL 0
+        SEND_CHANNEL
+        RETURN
+
+        START
+        LOAD_VAL 5
+        SET_PRIORITY
+        LOAD_VAL 1
+        LOAD_ADDR 'worker'
+        SPAWN 1
+        LOAD_VAL 2
+        LOAD_ADDR 'worker'
+        SPAWN 1
+        LOAD_VAL 0
+        SET_PRIORITY
+        JOIN
+        WRITE_VAR 'a'
+        JOIN
+        WRITE_VAR 'b'
+        LOAD_CHANNEL 0
+        RECV_CHANNEL
+    ";
+
+    #[test]
+    fn scheduler_policies() {
+        let threads = [
+            ThreadInfo { id: 0, priority: 0 },
+            ThreadInfo { id: 2, priority: 1 },
+            ThreadInfo { id: 5, priority: 1 },
+        ];
+        let mut round_robin = RoundRobinScheduler::new(4);
+        let picks: Vec<usize> = (0..4).map(|_| round_robin.next(&threads)).collect();
+        assert_eq!(picks, vec![0, 2, 5, 0]);
+        assert_eq!(round_robin.time_slice(), 4);
+
+        let mut priority = PriorityScheduler::new(1);
+        let picks: Vec<usize> = (0..3).map(|_| priority.next(&threads)).collect();
+        assert_eq!(picks, vec![2, 5, 2]);
+
+        // The last worker to send wins the channel
+        let main_result = |scheduler: Box<dyn Scheduler>| {
+            let mut interpreter = Interpreter::new(ByteCode::from_source(PRIORITY_SRC));
+            interpreter.set_scheduler(scheduler);
+            interpreter.run();
+            interpreter.thread_exit(interpreter.main_thread_id()).cloned()
+        };
+        assert_eq!(main_result(Box::new(RoundRobinScheduler::new(1))),
+            Some(ThreadExit::Returned(Some(StackItem::Value(2)))));
+        assert_eq!(main_result(Box::new(PriorityScheduler::new(1))),
+            Some(ThreadExit::Returned(Some(StackItem::Value(1)))));
+
+        // Two instructions and the return of the thread take three steps
+        let outcome = |step_limit: usize, parallel: bool| {
+            let mut interpreter = Interpreter::new(ByteCode::from_source("LOAD_VAL 1\nLOAD_VAL 2"));
+            interpreter.set_step_limit(step_limit);
+            match parallel {
+                true => interpreter.run_parallel(1),
+                false => interpreter.run(),
+            }
+        };
+        for parallel in [false, true] {
+            assert_eq!(outcome(0, parallel), RunOutcome::StepLimit);
+            assert_eq!(outcome(2, parallel), RunOutcome::StepLimit);
+            assert_eq!(outcome(3, parallel), RunOutcome::Finished);
+        }
+    }
+
+    // Traps when the writer of 0 is the last one to send
+    const RACE_SRC: &str = "
+        LABEL 'writer'
+        LOAD_CHANNEL 0
+        SEND_CHANNEL
+        RETURN
+
+        START
+        LOAD_VAL 1
+        LOAD_ADDR 'writer'
+        SPAWN 1
+        LOAD_VAL 0
+        LOAD_ADDR 'writer'
+        SPAWN 1
+        JOIN
+        WRITE_VAR 'a'
+        JOIN
+        WRITE_VAR 'b'
+        LOAD_CHANNEL 0
+        RECV_CHANNEL
+        JUMP_ZERO 'bad'
+        JUMP 'end'
+        LABEL 'bad'
+        READ_VAR 'unset'
+        LABEL 'end'
+    ";
+
+    #[test]
+    fn exploration_finds_and_replays_failures() {
+        let explorer = Explorer::new(|| ByteCode::from_source(RACE_SRC));
+
+        let reports = explorer.explore_random(0, 20);
+        assert!(!reports.is_empty() && reports.len() < 20);
+        for report in &reports {
+            assert!(matches!(report.failure, Failure::Trapped(0, _, _, _)));
+            assert_eq!(explorer.replay(&report.schedule).1, Some(report.failure.clone()));
+        }
+        assert_eq!(reports[0].failure.to_string(),
+            "thread 0 trapped at 23:9: Variable 'unset' is not set");
+        assert!(!explorer.explore_pct(0, 20, 2).is_empty());
+        let reports = explorer.explore_dfs(100, 100);
+        assert!(!reports.is_empty());
+        assert_eq!(explorer.replay(&reports[0].schedule).1, Some(reports[0].failure.clone()));
+
+        let explorer = Explorer::new(|| ByteCode::from_source("LOAD_CHANNEL 1\nRECV_CHANNEL"));
+        assert_eq!(explorer.explore_random(0, 1)[0].failure, Failure::Deadlock(vec![0]));
+    }
+
+    #[test]
+    fn sleep_on_virtual_clock() {
+        let byte_code = ByteCode::from_source("
+            LABEL 'slow'
+            SLEEP 300
+            LOAD_VAL 1
+            LOAD_CHANNEL 0
+            SEND_CHANNEL
+            NOW
+            RETURN_VALUE
+
+            LABEL 'fast'
+            YIELD
+            SLEEP 10
+            LOAD_VAL 2
+            LOAD_CHANNEL 0
+            SEND_CHANNEL
+            RETURN
+
+            START
+            LOAD_ADDR 'slow'
+            LOAD_ADDR 'fast'
+            SPAWN_N 2
+            JOIN
+            WRITE_VAR 'fast'
+            JOIN
+            WRITE_VAR 'slow'
+            LOAD_CHANNEL 0
+            RECV_CHANNEL
+        ");
+        let mut interpreter = Interpreter::new(byte_code);
+        interpreter.run();
+
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(1)))));
+        let slow = interpreter.thread_exit(1).unwrap().clone();
+        assert!(matches!(slow, ThreadExit::Returned(Some(StackItem::Value(t))) if t >= 300));
+
+        let source = format!("SLEEP {}\nSLEEP {}\nNOW", ValueType::MAX, ValueType::MAX);
+        let mut interpreter = Interpreter::new(ByteCode::from_source(&source));
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(ValueType::MAX)))));
+        let mut interpreter = Interpreter::new(ByteCode::from_source(&source));
+        assert_eq!(interpreter.run_parallel(2), RunOutcome::Finished);
+    }
+
+    const CONDVAR_SRC: &str = "
+        LABEL 'consumer'
+        LOCK 'm'
+        LABEL 'check'
+        READ_GLOBAL 'ready'
+        JUMP_ZERO 'wait'
+        WRITE_VAR 'ready'
+        UNLOCK 'm'
+        READ_GLOBAL 'data'
+        RETURN_VALUE
+        LABEL 'wait'
+        WRITE_VAR 'ready'
+        WAIT 'm'
+        JUMP 'check'
+
+        LABEL 'stray'
+        UNLOCK 'm'
+
+        START
+        LOAD_VAL 0
+        WRITE_GLOBAL 'ready'
+        LOAD_ADDR 'stray'
+        LOAD_ADDR 'consumer'
+        SPAWN_N 2
+        YIELD
+        LOCK 'm'
+        LOAD_VAL 42
+        WRITE_GLOBAL 'data'
+        LOAD_VAL 1
+        WRITE_GLOBAL 'ready'
+        NOTIFY 'm'
+        UNLOCK 'm'
+        JOIN
+        WRITE_VAR 'data'
+        JOIN
+        READ_VAR 'data'
+    ";
+
+    #[test]
+    fn mutex_and_condition_variable() {
+        let mut interpreter = Interpreter::new(ByteCode::from_source(CONDVAR_SRC));
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(42)))));
+        assert_eq!(interpreter.thread_exit(1),
+            Some(&ThreadExit::Trapped(String::from("Thread 1 unlocked mutex 'm' it does not hold"))));
+
+        // Only the stray unlock fails, whatever the interleaving
+        let explorer = Explorer::new(|| ByteCode::from_source(CONDVAR_SRC));
+        for report in explorer.explore_random(0, 30) {
+            assert!(matches!(report.failure, Failure::Trapped(1, _, _, _)));
+        }
+    }
+
+    #[test]
+    fn variables_resolve_to_slots() {
+        // Functions run on the thread that calls them, so a name is the same
+        // slot everywhere
+        let byte_code = ByteCode::from_source("
+            LABEL 'set'
+            LOAD_VAL 5
+            WRITE_VAR 'x'
+            RETURN
+            LABEL 'get'
+            READ_VAR 'x'
+            RETURN_VALUE
+            START
+            CALL 'set'
+            CALL 'get'
+        ");
+        let slot = byte_code.variable_slot("x").unwrap();
+        assert_eq!(byte_code.variable_name(slot), Some("x"));
+        assert!(matches!(byte_code.get_line(2), Some(Instruction::WriteVar(s)) if *s == slot));
+        assert!(matches!(byte_code.get_line(5), Some(Instruction::ReadVar(s)) if *s == slot));
+        assert_eq!(byte_code.variable_slot("y"), None);
+        let mut interpreter = Interpreter::new(byte_code);
+        interpreter.run();
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(5)))));
+
+        // A name only read gets a slot too, and traps by its name
+        let mut interpreter = Interpreter::new(ByteCode::from_source("READ_VAR 'unknown'"));
+        interpreter.run();
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Trapped(String::from("Variable 'unknown' is not set"))));
+    }
+
+    #[test]
+    fn unset_global_traps() {
+        let mut interpreter = Interpreter::new(ByteCode::from_source("READ_GLOBAL 'missing'"));
+        interpreter.run();
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Trapped(String::from("Global 'missing' is not set"))));
+    }
+
+    #[test]
+    fn atomics_on_globals() {
+        // A CAS spin lock around a plain counter next to an atomic one
+        let explorer = Explorer::new(|| ByteCode::from_source("
+            LABEL 'worker'
+            LOAD_VAL 1
+            ATOMIC_ADD 'hits'
+            WRITE_VAR 'old'
+            LABEL 'acquire'
+            LOAD_VAL 0
+            LOAD_VAL 1
+            COMPARE_AND_SWAP 'lock'
+            JUMP_ZERO 'locked'
+            WRITE_VAR 'old'
+            YIELD
+            JUMP 'acquire'
+            LABEL 'locked'
+            WRITE_VAR 'old'
+            READ_GLOBAL 'count'
+            YIELD
+            LOAD_VAL 1
+            ADD
+            WRITE_GLOBAL 'count'
+            LOAD_VAL 0
+            FETCH_AND_SET 'lock'
+            RETURN_VALUE
+
+            START
+            LOAD_VAL 0
+            WRITE_GLOBAL 'count'
+            LOAD_ADDR 'worker'
+            LOAD_ADDR 'worker'
+            LOAD_ADDR 'worker'
+            SPAWN_N 3
+            JOIN
+            WRITE_VAR 'a'
+            JOIN
+            WRITE_VAR 'b'
+            JOIN
+            READ_VAR 'a'
+            ADD
+            READ_VAR 'b'
+            ADD
+            READ_GLOBAL 'count'
+            ADD
+            READ_GLOBAL 'hits'
+            ADD
+        "));
+        for seed in 0..20 {
+            let (interpreter, failure) = explorer.replay(&Schedule::Random(seed));
+            assert_eq!(failure, None);
+            assert_eq!(interpreter.thread_exit(0),
+                Some(&ThreadExit::Returned(Some(StackItem::Value(9)))));
+        }
+    }
+
+    const PARALLEL_SRC: &str = "
+        LABEL 'worker'
+        WRITE_VAR 'n'
+        LOAD_VAL 0
+        WRITE_VAR 'sum'
+        READ_VAR 'n'
+        LOOP
+        READ_VAR 'sum'
+        READ_VAR 'n'
+        ADD
+        WRITE_VAR 'sum'
+        LOCK 'm'
+        READ_GLOBAL 'count'
+        LOAD_VAL 1
+        ADD
+        WRITE_GLOBAL 'count'
+        UNLOCK 'm'
+        ENDLOOP
+        READ_VAR 'sum'
+        RETURN_VALUE
+
+        START
+        LOAD_VAL 0
+        WRITE_GLOBAL 'count'
+        LOAD_VAL 3
+        LOAD_ADDR 'worker'
+        SPAWN 1
+        LOAD_VAL 4
+        LOAD_ADDR 'worker'
+        SPAWN 1
+        LOAD_VAL 5
+        LOAD_ADDR 'worker'
+        SPAWN 1
+        JOIN
+        WRITE_VAR 'a'
+        JOIN
+        WRITE_VAR 'b'
+        JOIN
+        READ_VAR 'b'
+        ADD
+        READ_VAR 'a'
+        ADD
+        READ_GLOBAL 'count'
+        ADD
+    ";
+
+    #[test]
+    fn parallel_matches_cooperative() {
+        let expected = Some(ThreadExit::Returned(Some(StackItem::Value(62))));
+
+        let mut interpreter = Interpreter::new(ByteCode::from_source(PARALLEL_SRC));
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+        assert_eq!(interpreter.thread_exit(0).cloned(), expected);
+
+        for _ in 0..10 {
+            let mut interpreter = Interpreter::new(ByteCode::from_source(PARALLEL_SRC));
+            interpreter.set_scheduler(Box::new(RoundRobinScheduler::new(8)));
+            assert_eq!(interpreter.run_parallel(4), RunOutcome::Finished);
+            assert_eq!(interpreter.thread_exit(0).cloned(), expected);
+        }
+
+        let mut interpreter = Interpreter::new(ByteCode::from_source(PARALLEL_SRC));
+        interpreter.set_step_limit(20);
+        assert_eq!(interpreter.run(), RunOutcome::StepLimit);
+        let mut interpreter = Interpreter::new(ByteCode::from_source(PARALLEL_SRC));
+        interpreter.set_step_limit(20);
+        assert_eq!(interpreter.run_parallel(4), RunOutcome::StepLimit);
+
+        let mut interpreter = Interpreter::new(ByteCode::from_source("LOAD_CHANNEL 1\nRECV_CHANNEL"));
+        assert_eq!(interpreter.run_parallel(2), RunOutcome::Deadlock);
+    }
+
+    #[test]
+    fn thread_identity() {
+        let explorer = Explorer::new(|| ByteCode::from_source("
+            LABEL 'faulty'
+            SET_THREAD_NAME 'faulty'
+            ADD
+
+            LABEL 'worker'
+            THREAD_ID
+            RETURN_VALUE
+
+            START
+            SET_THREAD_NAME 'main'
+            LOAD_ADDR 'faulty'
+            LOAD_ADDR 'worker'
+            SPAWN_N 2
+            THREAD_COUNT
+            WRITE_VAR 'count'
+            JOIN
+            READ_VAR 'count'
+            ADD
+            THREAD_ID
+            ADD
+        "));
+        let (interpreter, failure) = explorer.replay(&Schedule::Random(0));
+
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(5)))));
+        assert_eq!(interpreter.thread_name(0), Some("main"));
+        assert_eq!(interpreter.thread_label(2), "thread 2");
+        let failure = failure.unwrap().to_string();
+        assert!(failure.starts_with("thread 1 'faulty' trapped"), "{}", failure);
+    }
+
+    const SUPERVISION_SRC: &str = "
+        LABEL 'looper'
+        CHECK_CANCEL
+        SLEEP 10
+        JUMP 'looper'
+
+        LABEL 'spinner'
+        SLEEP 10
+        JUMP 'spinner'
+
+        LABEL 'faulty'
+        SLEEP 20
+        ADD
+
+        START
+        LOAD_ADDR 'looper'
+        LOAD_ADDR 'spinner'
+        LOAD_ADDR 'faulty'
+        SPAWN_N 3
+        LOAD_CHANNEL 0
+        MONITOR
+        LOAD_CHANNEL 0
+        RECV_CHANNEL
+        WRITE_VAR 'down'
+        KILL
+        CANCEL
+        READ_VAR 'down'
+    ";
+
+    #[test]
+    fn cancellation_and_monitors() {
+        let check = |interpreter: &Interpreter| {
+            assert_eq!(interpreter.thread_exit(0),
+                Some(&ThreadExit::Returned(Some(StackItem::Value(3)))));
+            assert_eq!(interpreter.thread_exit(1), Some(&ThreadExit::Cancelled));
+            assert_eq!(interpreter.thread_exit(2), Some(&ThreadExit::Cancelled));
+            assert!(matches!(interpreter.thread_exit(3), Some(ThreadExit::Trapped(_))));
+        };
+
+        let mut interpreter = Interpreter::new(ByteCode::from_source(SUPERVISION_SRC));
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+        check(&interpreter);
+
+        let mut interpreter = Interpreter::new(ByteCode::from_source(SUPERVISION_SRC));
+        assert_eq!(interpreter.run_parallel(3), RunOutcome::Finished);
+        check(&interpreter);
+    }
+
+    #[test]
+    fn killed_thread_releases_its_mutex() {
+        let source = "
+            LABEL 'holder'
+            LOCK 'm'
+            SLEEP 100
+            UNLOCK 'm'
+
+            START
+            LOAD_ADDR 'holder'
+            SPAWN
+            SLEEP 5
+            KILL
+            LOCK 'm'
+            UNLOCK 'm'
+            LOAD_VAL 7
+        ";
+        let mut interpreter = Interpreter::new(ByteCode::from_source(source));
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(7)))));
+        assert_eq!(interpreter.thread_exit(1), Some(&ThreadExit::Cancelled));
+    }
+
+    const ACTOR_SRC: &str = "
+        LABEL 'doubler'
+        WRITE_VAR 'parent'
+        RECEIVE
+        LOAD_VAL 2
+        MULTIPLY
+        READ_VAR 'parent'
+        SEND_TO
+        RETURN
+
+        START
+        LOAD_VAL 21
+        THREAD_ID
+        LOAD_ADDR 'doubler'
+        SPAWN 1
+        SEND_TO
+        RECEIVE
+    ";
+
+    #[test]
+    fn actor_mailboxes() {
+        let mut interpreter = Interpreter::new(ByteCode::from_source(ACTOR_SRC));
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(42)))));
+
+        for _ in 0..5 {
+            let mut interpreter = Interpreter::new(ByteCode::from_source(ACTOR_SRC));
+            assert_eq!(interpreter.run_parallel(2), RunOutcome::Finished);
+            assert_eq!(interpreter.thread_exit(0),
+                Some(&ThreadExit::Returned(Some(StackItem::Value(42)))));
+        }
+
+        // Only checked at run time, a spawned thread gets what it is given
+        let mut interpreter = Interpreter::new(ByteCode::from_source("
+            LABEL 'send'
+            SEND_TO
+            RETURN
+
+            START
+            LOAD_VAL 1
+            LOAD_ADDR 'send'
+            LOAD_ADDR 'send'
+            SPAWN 2
+            JOIN
+        "));
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+        assert_eq!(interpreter.thread_exit(1),
+            Some(&ThreadExit::Trapped(String::from("StackItem is not Thread"))));
+        assert_eq!(interpreter.thread_exit(0), Some(&ThreadExit::Returned(Some(StackItem::Trapped))));
+    }
+
+    #[test]
+    fn undefined_labels_fail_to_link() {
+        let err = ByteCode::link("
+            START
+            LOAD_VAL 0
+            JUMP_ZERO 'missing'
+            CALL 'nowhere'
+            JUMP 'missing'
+        ").err().unwrap();
+        assert_eq!(err.undefined, vec![String::from("missing"), String::from("nowhere")]);
+        assert_eq!(err.to_string(), "undefined labels 'missing', 'nowhere'");
+
+        let err = ByteCode::link("LABEL 'f'\nRETURN\nLABEL 'f'\nRETURN").err().unwrap();
+        assert_eq!(err.to_string(), "line 3, column 1: label 'f' is defined twice");
+    }
+
+    struct Scale {
+        factor: ValueType,
+    }
+    impl Expr for Scale {
+        fn name(&self) -> &'static str {
+            "Scale"
+        }
+
+        fn uses_globals(&self) -> bool {
+            false
+        }
+
+        fn eval(&self,
+            _thread_global: &mut VariableTable<ValueType>,
+            stack: &mut Stack<StackItem>,
+            _var_table: &mut VariableSlots<ValueType>,
+            _func_table: &VariableTable<AddrType>,
+            _control_flow: &mut ControlFlow
+        ) -> Result<(), Trap> {
+            let val = stack.pop_value()?;
+            stack.push(StackItem::Value(val * self.factor));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn host_instructions() {
+        let mut parse_engine = ParseEngine::default();
+        parse_engine.register("SCALE", |value, _| Box::new(Scale { factor: value.unwrap() }));
+        let byte_code = ByteCode::link_with("
+            START
+            LOAD_VAL 7
+            SCALE 6
+        ", &parse_engine).unwrap();
+
+        let mut interpreter = Interpreter::new(byte_code);
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(42)))));
+    }
+
+    #[test]
+    fn compiled_byte_code_round_trip() {
+        let byte_code = ByteCode::from_source(PARALLEL_SRC);
+        let mut brc = Vec::new();
+        byte_code.write_to(&mut brc, true).unwrap();
+
+        let loaded = ByteCode::read_from(&mut brc.as_slice()).unwrap();
+        assert_eq!(loaded.start_addr(), byte_code.start_addr());
+        assert_eq!(loaded.get_labels().read("worker"), byte_code.get_labels().read("worker"));
+        assert_eq!(loaded.variable_slot("sum"), byte_code.variable_slot("sum"));
+        let mut interpreter = Interpreter::new(loaded);
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(62)))));
+
+        let mut version = brc.clone();
+        version[4] = 9;
+        assert!(matches!(ByteCode::read_from(&mut version.as_slice()),
+            Err(FormatError::Version(9))));
+        assert!(matches!(ByteCode::read_from(&mut &brc[..brc.len() - 3]),
+            Err(FormatError::Truncated)));
+        let mut corrupt = brc.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(matches!(ByteCode::read_from(&mut corrupt.as_slice()),
+            Err(FormatError::Checksum)));
+        let mut trailing = brc.clone();
+        trailing.extend_from_slice(&[0, 0]);
+        assert!(matches!(ByteCode::read_from(&mut trailing.as_slice()),
+            Err(FormatError::Invalid(message)) if message == "2 bytes after the payload"));
+        // Covered by the length and the checksum, but not by the instructions
+        let payload_len = brc.len() - 14 + 2;
+        trailing[6..10].copy_from_slice(&(payload_len as u32).to_le_bytes());
+        let sum = crate::binary::checksum(&trailing[14..]);
+        trailing[10..14].copy_from_slice(&sum.to_le_bytes());
+        assert!(matches!(ByteCode::read_from(&mut trailing.as_slice()),
+            Err(FormatError::Invalid(message)) if message == "2 bytes after the instructions"));
+
+        // Files that decode but would not run are rejected before running
+        let invalid = |edit: fn(&mut ByteCode)| {
+            let mut byte_code = ByteCode::from_source("
+                START
+                LOAD_VAL 1
+                WRITE_VAR 'x'
+            ");
+            edit(&mut byte_code);
+            let mut brc = Vec::new();
+            byte_code.write_to(&mut brc, false).unwrap();
+            match ByteCode::read_from(&mut brc.as_slice()) {
+                Err(FormatError::Invalid(message)) => message,
+                other => panic!("expected an invalid file, got {:?}", other.map(|_| ())),
+            }
+        };
+        assert_eq!(invalid(|byte_code| {
+            byte_code.exprs.push(Instruction::ReadVar(u32::MAX as usize - 1));
+        }), format!("{} variable slots for 4 instructions", u32::MAX));
+        assert_eq!(invalid(|byte_code| byte_code.exprs.push(Instruction::Jump(99))),
+            "Jump at 3 jumps to 99 past the end");
+        assert_eq!(invalid(|byte_code| byte_code.start_addr = 7),
+            "start address 7 is past the end");
+        assert_eq!(invalid(|byte_code| {
+            byte_code.exprs.push(Instruction::EndLoop { loop_var: 0, loopstart: 1 });
+        }), "EndLoop at 3 does not match its block");
+        assert_eq!(invalid(|byte_code| byte_code.exprs[1] = Instruction::Noop),
+            "at 2: WriteVar pops from an empty stack");
+    }
+
+    #[test]
+    fn disassembly_round_trip() {
+        let test_br = std::fs::read_to_string("test.br").unwrap();
+        let sources = [test_br.as_str(), PRIORITY_SRC, RACE_SRC, CONDVAR_SRC, PARALLEL_SRC,
+            SUPERVISION_SRC, ACTOR_SRC];
+        for source in sources {
+            let byte_code = ByteCode::from_source(source);
+            let text = byte_code.disassemble();
+            let reassembled = ByteCode::from_source(&text);
+            assert_eq!(reassembled.disassemble(), text);
+
+            // Source positions differ, the disassembly has lines of its own
+            let (mut brc, mut reassembled_brc) = (Vec::new(), Vec::new());
+            byte_code.write_to(&mut brc, false).unwrap();
+            reassembled.write_to(&mut reassembled_brc, false).unwrap();
+            assert_eq!(reassembled_brc, brc);
+            assert_eq!(reassembled.variables, byte_code.variables);
+        }
+    }
+
+    #[test]
+    fn verifier_reports_every_violation() {
+        let messages = |source: &str| -> Vec<String> {
+            let err = ByteCode::link(source).err().unwrap();
+            err.violations.iter().map(|violation| violation.to_string()).collect()
+        };
+
+        assert_eq!(messages("
+            START
+            ENDLOOP
+            LOOP
+            WHILE
+            ENDLOOP
+        "), vec![
+            "line 3, column 13: ENDLOOP without a LOOP",
+            "line 4, column 13: LOOP is never closed",
+            "line 5, column 13: WHILE is never closed",
+            "line 6, column 13: ENDLOOP closes a WHILE",
+        ]);
+
+        assert_eq!(messages("
+            START
+            LOAD_CHANNEL 0
+            ADD
+            LOAD_VAL 1
+            JUMP_ZERO 'skip'
+            LOAD_VAL 2
+            LABEL 'skip'
+            WRITE_VAR 'x'
+            LOAD_ADDR 'f'
+            JOIN
+            RETURN
+            LABEL 'f'
+        "), vec![
+            "line 4, column 13: Add expects a Value but finds a Channel",
+            "line 4, column 13: Add pops from an empty stack",
+            "line 9, column 13: stack depth is 2 on one path and 3 on another",
+            "line 11, column 13: Join expects a Thread but finds an Addr",
+        ]);
+    }
+
+    // Straight line code with folds, tees, skipped blocks and labels for the
+    // optimizer to find
+    fn random_program(rng: &mut Rng) -> String {
+        let mut lines = vec![String::from("START"), String::from("LOAD_VAL 1")];
+        let mut depth = 1;
+        for segment in 0..rng.below(30) {
+            let var = ["x", "y", "z"][rng.below(3)];
+            match rng.below(7) {
+                0 => {
+                    let val = match rng.below(10) {
+                        0 => ValueType::MAX,
+                        _ => rng.below(20) as ValueType,
+                    };
+                    lines.push(format!("LOAD_VAL {}", val));
+                    depth += 1;
+                },
+                1 if depth >= 2 => {
+                    lines.push(String::from(["ADD", "MULTIPLY"][rng.below(2)]));
+                    depth -= 1;
+                },
+                2 if depth >= 2 => {
+                    lines.push(format!("WRITE_VAR '{}'", var));
+                    depth -= 1;
+                },
+                3 => {
+                    lines.push(format!("WRITE_VAR '{}'", var));
+                    lines.push(format!("READ_VAR '{}'", var));
+                },
+                4 => {
+                    lines.push(format!("READ_VAR '{}'", var));
+                    depth += 1;
+                },
+                5 => {
+                    lines.push(format!("JUMP 'skip{}'", segment));
+                    lines.push(String::from("LOAD_VAL 7"));
+                    lines.push(format!("WRITE_VAR '{}'", var));
+                    lines.push(format!("LABEL 'skip{}'", segment));
+                },
+                _ => lines.push(format!("LABEL 'label{}'", segment)),
+            }
+        }
+        lines.join("\n")
+    }
+
+    #[test]
+    fn optimized_runs_match_unoptimized() {
+        let test_br = std::fs::read_to_string("test.br").unwrap();
+        let mut sources: Vec<String> = [test_br.as_str(), PARALLEL_SRC, ACTOR_SRC, "
+            START
+            LOAD_VAL 2
+            LOAD_VAL 3
+            MULTIPLY
+            WRITE_VAR 'x'
+            READ_VAR 'x'
+            JUMP 'a'
+            LABEL 'b'
+            JUMP 'c'
+            LABEL 'a'
+            JUMP 'b'
+            LABEL 'c'
+            LOAD_VAL 4
+            LOOP
+            READ_VAR 'x'
+            LOAD_VAL 1
+            ADD
+            WRITE_VAR 'x'
+            ENDLOOP
+            READ_VAR 'x'
+            ADD
+        "].iter().map(|source| source.to_string()).collect();
+        let mut rng = Rng::new(43);
+        sources.extend((0..50).map(|_| random_program(&mut rng)));
+
+        for source in &sources {
+            let mut optimized = ByteCode::from_source(source);
+            optimized.optimize();
+            assert!(crate::verify::verify(&optimized.exprs, optimized.start_addr).is_empty());
+            assert!(!optimized.exprs.iter().any(|expr|
+                matches!(expr, Instruction::Noop | Instruction::Label | Instruction::Start)));
+
+            let mut interpreter = Interpreter::new(ByteCode::from_source(source));
+            let mut optimized = Interpreter::new(optimized);
+            assert_eq!(optimized.run(), interpreter.run());
+            assert_eq!(optimized.thread_exits(), interpreter.thread_exits());
+        }
+    }
+
+    #[test]
+    fn warnings_for_dead_code_and_unused_names() {
+        let mut byte_code = ByteCode::from_source("
+            LABEL 'helper'
+            LOAD_VAL 1
+            RETURN_VALUE
+            LOAD_VAL 2
+
+            LABEL 'unused'
+            START
+            LOAD_VAL 3
+            WRITE_VAR 'x'
+            JUMP 'end'
+            LOAD_VAL 4
+            WRITE_VAR 'z'
+            LABEL 'end'
+            READ_VAR 'y'
+            LOAD_ADDR 'helper'
+            SPAWN
+            JOIN
+        ");
+        let warnings: Vec<String> = byte_code.warnings().iter()
+                .map(|warning| warning.to_string())
+                .collect();
+        assert_eq!(warnings, vec![
+            "line 5, column 13: warning: unreachable instruction is never run",
+            "line 7, column 13: warning: label 'unused' is never used",
+            "line 10, column 13: warning: variable 'x' is never read",
+            "line 12, column 13: warning: 2 unreachable instructions are never run",
+            "line 13, column 13: warning: variable 'z' is never read",
+            "line 15, column 13: warning: variable 'y' is never written",
+        ]);
+        byte_code.set_file("lint.br");
+        assert_eq!(byte_code.warnings()[0].to_string(),
+            "lint.br:5:13: warning: unreachable instruction is never run");
+
+        let graph = byte_code.control_flow_graph();
+        assert_eq!(graph.blocks.len(), 6);
+        assert!(ByteCode::from_source(PARALLEL_SRC).warnings().iter()
+            .all(|warning| warning.message.starts_with("variable")));
+    }
+
+    #[test]
+    fn control_flow_graph_as_dot() {
+        let byte_code = ByteCode::from_source("
+            LABEL 'double'
+            LOAD_VAL 2
+            MULTIPLY
+            RETURN_VALUE
+
+            START
+            LOAD_VAL 3
+            JUMP_ZERO 'skip'
+            CALL 'double'
+            LABEL 'skip'
+            LOAD_ADDR 'double'
+            SPAWN
+        ");
+        assert_eq!(byte_code.to_dot(), "\
+digraph bytecode {
+    node [shape=box, fontname=\"monospace\"];
+    b0000 [label=\"0000  LABEL 'double'\\l0001  LOAD_VAL 2\\l0002  MULTIPLY\\l0003  RETURN_VALUE\\l\"];
+    b0004 [label=\"0004  START\\l0005  LOAD_VAL 3\\l0006  JUMP_ZERO 'skip'\\l\", peripheries=2];
+    b0007 [label=\"0007  CALL 'double'\\l\"];
+    b0008 [label=\"0008  LABEL 'skip'\\l0009  LOAD_ADDR 'double'\\l\"];
+    b0010 [label=\"0010  SPAWN\\l\"];
+    b0004 -> b0008 [label=\"taken\"];
+    b0004 -> b0007 [label=\"not taken\"];
+    b0007 -> b0000 [label=\"call\"];
+    b0007 -> b0008 [label=\"fallthrough\"];
+    b0008 -> b0010 [label=\"fallthrough\"];
+    b0008 -> b0000 [label=\"spawn\"];
+}
+");
+    }
+
+    #[test]
+    fn repl_keeps_the_main_thread() {
+        let mut repl = Repl::new();
+        let mut eval = |lines: &str| -> String {
+            lines.lines().map(|line| repl.eval_line(line)).collect()
+        };
+
+        assert_eq!(eval("LOAD_VAL 3\nWRITE_VAR 'x'"), "[Value(3)]\n[]\nx = 3\n");
+        assert_eq!(eval("LABEL 'three'\nREAD_VAR 'x'\nRETURN_VALUE"), "[]\nx = 3\n");
+        assert_eq!(eval("LOAD_VAL 2\nLOOP\nCALL 'three'\n"), "[Value(2)]\nx = 3\n");
+        assert_eq!(eval("ENDLOOP\nADD"), "[Value(3), Value(3)]\nx = 3\n[Value(6)]\nx = 3\n");
+        assert_eq!(eval("READ_VAR 'y'"), "error: trapped: Variable 'y' is not set\n");
+        assert_eq!(eval(":stack\n:vars"), "[Value(6)]\nx = 3\n");
+        assert_eq!(eval(":reset\n:stack\nADD"), "[]\nerror: line 1, column 1: Add pops from an empty stack\n");
+
+        // A failed input is undone on the main thread and the threads it
+        // started are cancelled. Nothing runs again, so the clock goes on
+        assert_eq!(eval("LABEL 'f'\nRETURN\nLABEL 'f'\nRETURN"),
+            "[]\nerror: line 1, column 1: label 'f' is defined twice\n");
+        assert_eq!(eval("LABEL 'idle'\nSLEEP 1000\nRETURN"), "[]\n");
+        assert_eq!(eval("NOW\nWRITE_VAR 't'"), "[Value(2)]\n[]\nt = 2\n");
+        assert_eq!(eval("LOAD_VAL 1\nLOOP\nLOAD_ADDR 'idle'\nSPAWN\nPOP\nREAD_VAR 'nope'\nPOP\nENDLOOP"),
+            "[Value(1)]\nt = 2\nerror: trapped: Variable 'nope' is not set\n");
+        assert_eq!(eval("THREAD_COUNT\nNOW"), "[Value(1), Value(1)]\nt = 2\n[Value(1), Value(1), Value(12)]\nt = 2\n");
+    }
+
+    const WORKERS_HL: &str = "
+        fn square(x) { return x * x; }
+        fn worker(n) {
+            let sum = 0;
+            repeat n { sum = sum + square(n); }
+            if n == 3 { send(0, sum); } else { send(1, sum); }
+        }
+
+        let n = 3;
+        spawn worker(n);
+        spawn worker(4);
+        let total = recv(0) + recv(1);
+        if total == 91 { total = total - 1; } else { total = 0; }
+        while n != 0 { n = n - 1; }
+        return total * 2 + -n;
+    ";
+
+    #[test]
+    fn high_level_language_compiles_to_byte_code() {
+        let mut interpreter = Interpreter::new(ByteCode::compile(WORKERS_HL).unwrap());
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+        assert_eq!(interpreter.thread_exit(0),
+            Some(&ThreadExit::Returned(Some(StackItem::Value(180)))));
+
+        // Comparisons do no arithmetic, so they work for every value
+        let run = |source: &str| {
+            let mut interpreter = Interpreter::new(ByteCode::compile(source).unwrap());
+            interpreter.run();
+            interpreter.thread_exit(0).cloned().unwrap()
+        };
+        let value = |val| ThreadExit::Returned(Some(StackItem::Value(val)));
+        let extremes = "let a = 9223372036854775807; let b = -a - 1;";
+        assert_eq!(run(&format!("{} return (a == b) + (a != b) * 2 + (b == b) * 4;", extremes)), value(6));
+        assert_eq!(run(&format!("{} let c = -1; if a == c {{ return 1; }} return 2;", extremes)), value(2));
+        assert_eq!(run(&format!("{} return b - -1;", extremes)), value(i64::MIN + 1));
+        assert_eq!(run(&format!("{} return -b;", extremes)),
+            ThreadExit::Trapped(String::from("Subtract overflows")));
+        assert_eq!(run(&format!("{} return a - -1;", extremes)),
+            ThreadExit::Trapped(String::from("Subtract overflows")));
+
+        let error = |source: &str| ByteCode::compile(source).err().unwrap().to_string();
+        assert_eq!(error("let x = 1;\nx = y;"), "line 2, column 5: variable y is not defined");
+        assert_eq!(error("if 1 { let z = 1; }\nz;"), "line 2, column 1: variable z is not defined");
+        assert_eq!(error("let a = (1 + 2;"), "line 1, column 15: expected ')' but found ';'");
+        assert_eq!(error("fn f(a) { return a; }\nf(1, 2);"),
+            "line 2, column 1: function f takes 1 arguments but is given 2");
+        assert_eq!(error("fn f() { return g(); }\nfn g() { return f(); }"),
+            "line 1, column 17: function f can not call g, which calls f again");
+        assert_eq!(error("while 1 {"), "line 1, column 10: expected '}' but found the end of the source");
+    }
+
+    #[test]
+    fn source_positions_of_instructions() {
+        let source = "; adds one too many\nSTART\n\n    LOAD_VAL 9223372036854775807\n    LOAD_VAL 1\n      ADD\n";
+        let mut byte_code = ByteCode::link(source).unwrap();
+        byte_code.set_file("overflow.br");
+        assert_eq!(byte_code.source_pos(3), Some(SourcePos { line: 6, column: 7 }));
+
+        // Kept by the binary format and moved along by the optimizer
+        let mut brc = Vec::new();
+        byte_code.write_to(&mut brc, true).unwrap();
+        let mut loaded = ByteCode::read_from(&mut brc.as_slice()).unwrap();
+        loaded.optimize();
+        assert_eq!(loaded.location(2).as_deref(), Some("overflow.br:6:7"));
+        for byte_code in [byte_code, loaded] {
+            let mut interpreter = Interpreter::new(byte_code);
+            interpreter.run();
+            assert_eq!(interpreter.thread_exit(0),
+                Some(&ThreadExit::Trapped(String::from("Add overflows"))));
+            assert_eq!(interpreter.trap_location(0).as_deref(), Some("overflow.br:6:7"));
+        }
+
+        let mut err = ByteCode::link("START\n\n  BOGUS\nLOAD_VAL 'x'\nSPAWN_N 99999999999999999999").err().unwrap();
+        let messages: Vec<String> = err.violations.iter().map(|violation| violation.to_string()).collect();
+        assert_eq!(messages, vec![
+            "line 3, column 3: keyword BOGUS is not recognized",
+            "line 4, column 1: LOAD_VAL takes no name",
+            "line 5, column 1: SPAWN_N 99999999999999999999 is out of range",
+        ]);
+        err.set_file("bogus.br");
+        assert_eq!(err.violations[0].pos, SourcePos { line: 3, column: 3 });
+        assert_eq!(err.to_string().lines().next(), Some("bogus.br:3:3: keyword BOGUS is not recognized"));
+
+        let mut err = ByteCode::link("JUMP 'missing'").err().unwrap();
+        err.set_file("missing.br");
+        assert_eq!(err.to_string(), "missing.br: undefined labels 'missing'");
+    }
+
+    // Keeps its own copy of the stacks from the pushes and pops
+    #[derive(Default)]
+    struct Recorder {
+        stacks: BTreeMap<usize, Vec<StackItem>>,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ExecutionObserver for Recorder {
+        fn on_instruction(&mut self, _thread: usize, _addr: AddrType, _expr: &Instruction) {
+            self.events.lock().unwrap().push(String::from("instruction"));
+        }
+
+        fn on_push(&mut self, thread: usize, item: &StackItem) {
+            self.stacks.entry(thread).or_default().push(*item);
+        }
+
+        fn on_pop(&mut self, thread: usize, item: &StackItem) {
+            assert_eq!(self.stacks.get_mut(&thread).unwrap().pop().as_ref(), Some(item));
+        }
+
+        fn on_spawn(&mut self, parent: usize, child: usize) {
+            self.events.lock().unwrap().push(format!("spawn {} {}", parent, child));
+        }
+
+        fn on_block(&mut self, thread: usize) {
+            self.events.lock().unwrap().push(format!("block {}", thread));
+        }
+
+        fn on_thread_exit(&mut self, thread: usize, exit: &ThreadExit) {
+            let stack = self.stacks.remove(&thread).unwrap_or_default();
+            self.events.lock().unwrap().push(format!("exit {} {:?} {:?}", thread, exit, stack));
+        }
+    }
+
+    #[test]
+    fn observer_sees_the_stacks_and_threads() {
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        let mut interpreter = Interpreter::new(ByteCode::compile(WORKERS_HL).unwrap());
+        interpreter.set_observer(Box::new(recorder));
+        assert_eq!(interpreter.run(), RunOutcome::Finished);
+
+        // Main waits on the channel until both workers sent
+        let events = events.lock().unwrap();
+        let threads: Vec<&str> = events.iter()
+                .filter(|event| *event != "instruction")
+                .map(String::as_str)
+                .collect();
+        assert_eq!(threads, vec![
+            "spawn 0 1",
+            "spawn 0 2",
+            "block 0",
+            "block 0",
+            "exit 1 Returned(Some(Value(0))) [Value(0)]",
+            "block 0",
+            "exit 2 Returned(Some(Value(0))) [Value(0)]",
+            "exit 0 Returned(Some(Value(180))) [Value(180)]",
+        ]);
+        assert!(events.len() > threads.len());
+
+        // Parallel runs report the instructions and the stacks as well
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        let mut interpreter = Interpreter::new(ByteCode::compile(WORKERS_HL).unwrap());
+        interpreter.set_observer(Box::new(recorder));
+        assert_eq!(interpreter.run_parallel(3), RunOutcome::Finished);
+        let events = events.lock().unwrap();
+        let mut exits: Vec<&str> = events.iter()
+                .filter(|event| event.starts_with("exit"))
+                .map(String::as_str)
+                .collect();
+        exits.sort();
+        assert_eq!(exits, vec![
+            "exit 0 Returned(Some(Value(180))) [Value(180)]",
+            "exit 1 Returned(Some(Value(0))) [Value(0)]",
+            "exit 2 Returned(Some(Value(0))) [Value(0)]",
+        ]);
+        assert!(events.iter().any(|event| event == "instruction"));
+    }
 }
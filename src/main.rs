@@ -0,0 +1,233 @@
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+use interest::bytecode::ByteCode;
+use interest::explore::Explorer;
+use interest::interpreter::{Interpreter, RunOutcome, StackItem, ThreadExit, STEP_LIMIT};
+use interest::repl::Repl;
+use interest::scheduler::{PriorityScheduler, RandomScheduler, RoundRobinScheduler, Scheduler};
+
+
+const USAGE: &str = "\
+usage: interest <command> <file> [options]
+       interest repl
+
+commands:
+    run <file>      run the program, the exit code is the value the main
+                    thread leaves on its stack
+    check <file>    link and verify the program, print errors and warnings
+    disasm <file>   print the loaded byte code as annotated .br text
+    explore <file>  run the program with random schedules and print the ones
+                    that trap or deadlock
+    repl            run instructions as they are typed, :help lists the
+                    commands of the repl
+
+<file> is .br source, compiled byte code if it ends in .brc, or source of the
+high level language if it ends in .hl. A file that can not be loaded exits
+with 2, a run that traps, deadlocks or hits the step limit with 1, and so
+does an explore that finds a failing schedule
+
+options for run:
+    --steps <n>             stop after n scheduling steps (default 1000)
+    --scheduler <policy>    round-robin, priority or random
+    --time-slice <n>        instructions a thread runs before the scheduler
+                            picks again (default 1)
+    --seed <n>              seed of the random scheduler (default 0)
+    --trace                 print every instruction and the stack after it
+
+options for explore:
+    --runs <n>              schedules to try (default 100)
+    --seed <n>              seed of the first schedule (default 0), a failing
+                            seed replays with run --scheduler random --seed
+";
+
+struct Options {
+    steps: usize,
+    scheduler: String,
+    time_slice: usize,
+    seed: u64,
+    trace: bool,
+    runs: u64,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: Option<String>) -> T {
+    match value.map(|value| value.parse()) {
+        Some(Ok(number)) => number,
+        _ => usage_error(&format!("{} needs a number", flag)),
+    }
+}
+
+// Flags not in allowed are unknown to the command
+fn parse_options(mut args: impl Iterator<Item = String>, allowed: &[&str]) -> Options {
+    let mut options = Options {
+        steps: STEP_LIMIT,
+        scheduler: String::from("round-robin"),
+        time_slice: 1,
+        seed: 0,
+        trace: false,
+        runs: 100,
+    };
+    while let Some(arg) = args.next() {
+        if !allowed.contains(&arg.as_str()) {
+            usage_error(&format!("unknown option {}", arg));
+        }
+        match arg.as_str() {
+            "--steps" => options.steps = number(&arg, args.next()),
+            "--scheduler" => match args.next() {
+                Some(policy) => options.scheduler = policy,
+                None => usage_error("--scheduler needs a policy"),
+            },
+            "--time-slice" => options.time_slice = number(&arg, args.next()),
+            "--seed" => options.seed = number(&arg, args.next()),
+            "--trace" => options.trace = true,
+            "--runs" => options.runs = number(&arg, args.next()),
+            _ => usage_error(&format!("unknown option {}", arg)),
+        }
+    }
+    options
+}
+
+// Exits with 2 when the file can not be read or does not link
+fn load(path: &str) -> ByteCode {
+    if path.ends_with(".brc") {
+        let loaded = File::open(path)
+                .map_err(|err| err.to_string())
+                .and_then(|mut file| ByteCode::read_from(&mut file).map_err(|err| err.to_string()));
+        return loaded.unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(2);
+        });
+    }
+
+    let source = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        process::exit(2);
+    });
+    let mut byte_code = match path.ends_with(".hl") {
+        true => ByteCode::compile(&source).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(2);
+        }),
+        false => ByteCode::link(&source).unwrap_or_else(|mut err| {
+            err.set_file(path);
+            eprintln!("{}", err);
+            process::exit(2);
+        }),
+    };
+    byte_code.set_file(path);
+    byte_code
+}
+
+fn run(path: &str, options: Options) -> i32 {
+    let scheduler: Box<dyn Scheduler> = match options.scheduler.as_str() {
+        "round-robin" => Box::new(RoundRobinScheduler::new(options.time_slice)),
+        "priority" => Box::new(PriorityScheduler::new(options.time_slice)),
+        "random" => Box::new(RandomScheduler::new(options.seed, options.time_slice)),
+        policy => usage_error(&format!("unknown scheduler {}", policy)),
+    };
+    let mut interpreter = Interpreter::new(load(path));
+    interpreter.set_scheduler(scheduler);
+    interpreter.set_step_limit(options.steps);
+    interpreter.set_trace(options.trace);
+
+    match interpreter.run() {
+        RunOutcome::Finished => {},
+        RunOutcome::Deadlock => {
+            eprintln!("deadlock, threads {:?} are blocked", interpreter.live_threads());
+            return 1;
+        },
+        RunOutcome::StepLimit => {
+            eprintln!("stopped after {} steps", options.steps);
+            return 1;
+        },
+    }
+    match interpreter.thread_exit(interpreter.main_thread_id()) {
+        Some(ThreadExit::Returned(Some(StackItem::Value(val)))) => *val as i32,
+        Some(ThreadExit::Trapped(message)) => {
+            let main = interpreter.main_thread_id();
+            match interpreter.trap_location(main) {
+                Some(location) =>
+                    eprintln!("{} trapped at {}: {}", interpreter.thread_label(main), location, message),
+                None => eprintln!("{} trapped: {}", interpreter.thread_label(main), message),
+            }
+            1
+        },
+        _ => 0,
+    }
+}
+
+fn explore(path: &str, options: Options) -> i32 {
+    // A file that does not load exits before the first run
+    load(path);
+    let explorer = Explorer::new(|| load(path));
+    let reports = explorer.explore_random(options.seed, options.runs);
+    for report in &reports {
+        println!("{}", report);
+    }
+    println!("{} of {} runs failed", reports.len(), options.runs);
+    match reports.is_empty() {
+        true => 0,
+        false => 1,
+    }
+}
+
+fn check(path: &str) -> i32 {
+    let byte_code = load(path);
+    for warning in byte_code.warnings() {
+        eprintln!("{}", warning);
+    }
+    println!("{}: ok", path);
+    0
+}
+
+fn repl() {
+    let mut repl = Repl::new();
+    let stdin = io::stdin();
+    loop {
+        print!("{}", if repl.is_pending() { ".. " } else { "> " });
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 || line.trim() == ":quit" {
+            break;
+        }
+        print!("{}", repl.eval_line(&line));
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let (command, path) = match (args.next(), args.next()) {
+        (Some(command), None) if command == "repl" => {
+            repl();
+            return;
+        },
+        (Some(command), Some(path)) => (command, path),
+        (Some(command), None) if command == "help" || command == "--help" => {
+            print!("{}", USAGE);
+            return;
+        },
+        _ => usage_error("expected a command and a file"),
+    };
+
+    if command != "run" && command != "explore" && args.len() > 0 {
+        usage_error(&format!("{} takes no options", command));
+    }
+    let code = match command.as_str() {
+        "run" => run(&path, parse_options(args,
+            &["--steps", "--scheduler", "--time-slice", "--seed", "--trace"])),
+        "explore" => explore(&path, parse_options(args, &["--runs", "--seed"])),
+        "check" => check(&path),
+        "disasm" => {
+            print!("{}", load(&path).disassemble());
+            0
+        },
+        _ => usage_error(&format!("unknown command {}", command)),
+    };
+    process::exit(code);
+}
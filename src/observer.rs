@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+
+use crate::bytecode::ByteCode;
+use crate::instruction::Instruction;
+use crate::interpreter::{AddrType, StackItem, ThreadExit, ThreadIdType};
+
+
+// Told what the threads of an interpreter do, installed with
+// Interpreter::set_observer. Parallel runs call it from the worker running
+// the thread, one call at a time
+pub trait ExecutionObserver: Send {
+    // After the thread ran the instruction at addr, and after the pushes and
+    // pops the instruction did
+    fn on_instruction(&mut self, _thread: ThreadIdType, _addr: AddrType, _expr: &Instruction) {}
+    fn on_push(&mut self, _thread: ThreadIdType, _item: &StackItem) {}
+    fn on_pop(&mut self, _thread: ThreadIdType, _item: &StackItem) {}
+    // Before the arguments are pushed on the stack of the child
+    fn on_spawn(&mut self, _parent: ThreadIdType, _child: ThreadIdType) {}
+    // The thread retries the instruction once another thread did something
+    fn on_block(&mut self, _thread: ThreadIdType) {}
+    fn on_thread_exit(&mut self, _thread: ThreadIdType, _exit: &ThreadExit) {}
+}
+
+// Prints every instruction and the stack after it, keeping a copy of the
+// stacks from the pushes and pops
+pub struct Tracer {
+    // file:line:column by address
+    locations: Vec<Option<String>>,
+    names: BTreeMap<ThreadIdType, String>,
+    stacks: BTreeMap<ThreadIdType, Vec<StackItem>>,
+}
+
+impl Tracer {
+    pub fn new(byte_code: &ByteCode) -> Self {
+        Tracer {
+            locations: (0..byte_code.end_addr()).map(|addr| byte_code.location(addr)).collect(),
+            names: BTreeMap::new(),
+            stacks: BTreeMap::new(),
+        }
+    }
+
+    fn label(&self, thread: ThreadIdType) -> String {
+        match self.names.get(&thread) {
+            Some(name) => format!("thread {} '{}'", thread, name),
+            None => format!("thread {}", thread),
+        }
+    }
+}
+
+impl ExecutionObserver for Tracer {
+    fn on_instruction(&mut self, thread: ThreadIdType, addr: AddrType, expr: &Instruction) {
+        match self.locations.get(addr).cloned().flatten() {
+            Some(location) => println!("{} at {}: {}", self.label(thread), location, expr.name()),
+            None => println!("{}: {}", self.label(thread), expr.name()),
+        }
+        println!("{:?}", self.stacks.get(&thread).map_or(&[][..], |stack| stack));
+        // The name is only set once the instruction is done
+        if let Instruction::SetThreadName(name) = expr {
+            self.names.insert(thread, name.clone());
+        }
+    }
+
+    fn on_push(&mut self, thread: ThreadIdType, item: &StackItem) {
+        self.stacks.entry(thread).or_default().push(*item);
+    }
+
+    fn on_pop(&mut self, thread: ThreadIdType, _item: &StackItem) {
+        self.stacks.entry(thread).or_default().pop();
+    }
+
+    fn on_thread_exit(&mut self, thread: ThreadIdType, _exit: &ThreadExit) {
+        self.stacks.remove(&thread);
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::BTreeSet;
+
+use crate::bytecode::ByteCode;
+use crate::instruction::Instruction;
+use crate::interpreter::{AddrType, VariableTable};
+
+
+impl ByteCode {
+    // Rewrites the loaded code into code with the same results that runs in
+    // fewer steps. Removes the LABEL, NOOP and START lines, so disassembling
+    // the optimized code no longer gives back the labels
+    pub fn optimize(&mut self) {
+        let leaders = self.leaders();
+        self.fold_constants(&leaders);
+        self.tee_variables(&leaders);
+        self.thread_jumps();
+        self.remove_noops();
+    }
+
+    // Lines that can be reached other than from the line before them
+    fn leaders(&self) -> BTreeSet<AddrType> {
+        let mut leaders = BTreeSet::from([self.start_addr]);
+        leaders.extend(self.labels.iter().map(|(_, addr)| addr));
+        for expr in &self.exprs {
+            match expr {
+                Instruction::Jump(line) | Instruction::JumpZero(line)
+                    | Instruction::LoadAddr(line) => { leaders.insert(*line); },
+                Instruction::Call { func_line, return_line } => {
+                    leaders.insert(*func_line);
+                    leaders.insert(*return_line);
+                },
+                Instruction::Loop { endloop, .. } => { leaders.insert(endloop + 1); },
+                Instruction::EndLoop { loopstart, .. } => { leaders.insert(*loopstart); },
+                Instruction::While { endwhile } => { leaders.insert(endwhile + 1); },
+                Instruction::EndWhile { whilestart } => { leaders.insert(*whilestart); },
+                _ => {},
+            }
+        }
+        leaders
+    }
+
+    // Address of the next line at or after addr that does something
+    fn skip_noops(&self, mut addr: AddrType) -> AddrType {
+        while let Some(Instruction::Noop | Instruction::Label | Instruction::Start) = self.exprs.get(addr) {
+            addr += 1;
+        }
+        addr
+    }
+
+    // LOAD_VAL a, LOAD_VAL b, ADD becomes LOAD_VAL a + b, unless it overflows
+    // and the ADD has to trap
+    fn fold_constants(&mut self, leaders: &BTreeSet<AddrType>) {
+        for addr in 0..self.exprs.len() {
+            let Instruction::LoadVal(a) = self.exprs[addr] else { continue };
+            let second = self.skip_noops(addr + 1);
+            let Some(&Instruction::LoadVal(b)) = self.exprs.get(second) else { continue };
+            let op = self.skip_noops(second + 1);
+            let val = match self.exprs.get(op) {
+                Some(Instruction::Add) => a.checked_add(b),
+                Some(Instruction::Multiply) => a.checked_mul(b),
+                _ => None,
+            };
+            let Some(val) = val else { continue };
+            if leaders.range(addr + 1..=op).next().is_some() {
+                continue;
+            }
+            self.exprs[addr] = Instruction::Noop;
+            self.exprs[second] = Instruction::Noop;
+            self.exprs[op] = Instruction::LoadVal(val);
+        }
+    }
+
+    // WRITE_VAR x, READ_VAR x becomes TEE_VAR x
+    fn tee_variables(&mut self, leaders: &BTreeSet<AddrType>) {
+        for addr in 0..self.exprs.len() {
+            let Instruction::WriteVar(slot) = self.exprs[addr] else { continue };
+            let read = self.skip_noops(addr + 1);
+            if self.exprs.get(read).is_some_and(|expr| matches!(expr, Instruction::ReadVar(s) if *s == slot))
+                && leaders.range(addr + 1..=read).next().is_none() {
+                self.exprs[addr] = Instruction::TeeVar(slot);
+                self.exprs[read] = Instruction::Noop;
+            }
+        }
+    }
+
+    // Jumps to a JUMP go straight to where it leads, jumps to the next line
+    // are dropped
+    fn thread_jumps(&mut self) {
+        for addr in 0..self.exprs.len() {
+            let line = match self.exprs[addr] {
+                Instruction::Jump(line) | Instruction::JumpZero(line) => line,
+                _ => continue,
+            };
+            let mut target = self.skip_noops(line);
+            // A cycle of jumps never gets anywhere, leave it as it is
+            let mut hops = 0;
+            while let Some(Instruction::Jump(next)) = self.exprs.get(target) {
+                if hops == self.exprs.len() {
+                    target = self.skip_noops(line);
+                    break;
+                }
+                target = self.skip_noops(*next);
+                hops += 1;
+            }
+
+            if target == self.skip_noops(addr + 1) {
+                self.exprs[addr] = Instruction::Noop;
+            } else {
+                self.exprs[addr].set_target(target);
+            }
+        }
+    }
+
+    // Drops the lines that do nothing and moves every address to where its
+    // line ends up
+    fn remove_noops(&mut self) {
+        // new_addr[addr] is where the next kept line at or after addr goes
+        let mut new_addr = Vec::with_capacity(self.exprs.len() + 1);
+        let mut kept = 0;
+        for expr in &self.exprs {
+            new_addr.push(kept);
+            if !matches!(expr, Instruction::Noop | Instruction::Label | Instruction::Start) {
+                kept += 1;
+            }
+        }
+        new_addr.push(kept);
+
+        // Source positions go with their lines
+        if !self.positions.is_empty() {
+            self.positions = self.positions.iter().zip(&self.exprs)
+                    .filter(|(_, expr)| !matches!(expr, Instruction::Noop | Instruction::Label | Instruction::Start))
+                    .map(|(pos, _)| *pos)
+                    .collect();
+        }
+        let exprs = std::mem::take(&mut self.exprs);
+        self.exprs = exprs.into_iter()
+                .filter(|expr| !matches!(expr, Instruction::Noop | Instruction::Label | Instruction::Start))
+                .collect();
+        for expr in &mut self.exprs {
+            match expr {
+                Instruction::Jump(line) | Instruction::JumpZero(line)
+                    | Instruction::LoadAddr(line)
+                    | Instruction::Loop { endloop: line, .. }
+                    | Instruction::EndLoop { loopstart: line, .. }
+                    | Instruction::While { endwhile: line }
+                    | Instruction::EndWhile { whilestart: line } => *line = new_addr[*line],
+                Instruction::Call { func_line, return_line } => {
+                    *func_line = new_addr[*func_line];
+                    *return_line = new_addr[*return_line];
+                },
+                _ => {},
+            }
+        }
+        self.start_addr = new_addr[self.start_addr];
+
+        let mut labels = VariableTable::default();
+        for (label, addr) in self.labels.iter() {
+            labels.write(label, new_addr[addr]);
+        }
+        self.labels = labels;
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use crate::bytecode::ByteCode;
+use crate::interpreter::{
+    AddrType, IThread, Interpreter, Kernel, RunOutcome, Step, ValueType, VariableTable
+};
+
+
+struct Pool<'a> {
+    state: Mutex<PoolState<'a>>,
+    // Signalled whenever the kernel changes, idle workers wait on it
+    changed: Condvar,
+    thread_global: Mutex<&'a mut VariableTable<ValueType>>,
+    byte_code: &'a ByteCode,
+    func_table: &'a VariableTable<AddrType>,
+    // Every instruction is reported under the lock of the kernel
+    observed: bool,
+}
+
+struct PoolState<'a> {
+    kernel: &'a mut Kernel,
+    // Threads taken out of the kernel by a worker
+    running: usize,
+    steps: usize,
+    outcome: Option<RunOutcome>,
+}
+
+impl Interpreter {
+    // Runs the threads on workers OS threads, a thread keeps its worker for a
+    // time slice and only instructions touching shared state take a lock
+    pub fn run_parallel(&mut self, workers: usize) -> RunOutcome {
+        let observed = self.kernel.observer.is_some();
+        let pool = Pool {
+            state: Mutex::new(PoolState {
+                kernel: &mut self.kernel,
+                running: 0,
+                steps: 0,
+                outcome: None,
+            }),
+            changed: Condvar::new(),
+            thread_global: Mutex::new(&mut self.thread_global),
+            byte_code: &self.byte_code,
+            func_table: &self.func_table,
+            observed,
+        };
+
+        thread::scope(|scope| {
+            for _ in 0..workers.max(1) {
+                scope.spawn(|| pool.work());
+            }
+        });
+
+        let state = pool.state.into_inner().unwrap();
+        state.outcome.unwrap()
+    }
+}
+
+impl<'a> Pool<'a> {
+    fn work(&self) {
+        // Handed to the instructions that do not use the globals
+        let mut scratch = VariableTable::default();
+        while let Some((mut thread, time_slice)) = self.take_thread() {
+            let mut ran = 0;
+            let mut step = Step::Ran;
+            // A thread that blocks goes back under the same lock, so that it
+            // can not miss the event waking it up
+            let mut state = loop {
+                if ran == time_slice {
+                    break self.state.lock().unwrap();
+                }
+                ran += 1;
+
+                let expr = match self.byte_code.get_line(thread.addr) {
+                    Some(expr) => expr,
+                    None => {
+                        let mut state = self.state.lock().unwrap();
+                        step = state.kernel.finish(&mut thread);
+                        break state;
+                    },
+                };
+                let addr = thread.addr;
+                if self.observed {
+                    thread.stack.journal = Some(Vec::new());
+                }
+                let control_flow = if expr.uses_globals() {
+                    let mut thread_global = self.thread_global.lock().unwrap();
+                    thread.execute(expr, &mut thread_global, self.func_table, self.byte_code)
+                } else {
+                    thread.execute(expr, &mut scratch, self.func_table, self.byte_code)
+                };
+                if self.observed {
+                    self.state.lock().unwrap().kernel.observe_instruction(&mut thread, addr, expr);
+                }
+                if !thread.advance(&control_flow) {
+                    let mut state = self.state.lock().unwrap();
+                    // The kernel pushes the results of some instructions itself
+                    if self.observed {
+                        thread.stack.journal = Some(Vec::new());
+                    }
+                    step = state.kernel.apply(&mut thread, control_flow);
+                    state.kernel.observe_stack(&mut thread);
+                    if !matches!(step, Step::Ran) {
+                        break state;
+                    }
+                    drop(state);
+                    self.changed.notify_all();
+                }
+            };
+
+            state.running -= 1;
+            state.steps += ran;
+            state.kernel.clock = state.kernel.clock.saturating_add(ran as ValueType);
+            if !matches!(step, Step::Exited) && !state.kernel.take_kill(&thread) {
+                state.kernel.deliver_mail(&mut thread);
+                state.kernel.threads.insert(thread.get_id(), thread);
+            }
+            drop(state);
+            self.changed.notify_all();
+        }
+    }
+
+    fn take_thread(&self) -> Option<(IThread, usize)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.outcome.is_some() {
+                return None;
+            }
+            if state.kernel.threads.is_empty() && state.running == 0 {
+                return self.finish(state, RunOutcome::Finished);
+            }
+            if state.steps >= state.kernel.step_limit {
+                return self.finish(state, RunOutcome::StepLimit);
+            }
+
+            let runnable = state.kernel.runnable();
+            if !runnable.is_empty() {
+                let id = state.kernel.scheduler.next(&runnable);
+                let time_slice = state.kernel.scheduler.time_slice().max(1);
+                let thread = state.kernel.threads.remove(&id).unwrap();
+                state.running += 1;
+                return Some((thread, time_slice));
+            }
+
+            if state.running > 0 {
+                state = self.changed.wait(state).unwrap();
+                continue;
+            }
+            match state.kernel.next_wake() {
+                // Only sleeping threads left, skip ahead to the first deadline
+                Some(wake_at) => state.kernel.clock = wake_at,
+                // Every thread is blocked, nothing can wake them up
+                None => return self.finish(state, RunOutcome::Deadlock),
+            }
+        }
+    }
+
+    fn finish(&self,
+        mut state: MutexGuard<PoolState<'a>>,
+        outcome: RunOutcome
+    ) -> Option<(IThread, usize)> {
+        state.outcome = Some(outcome);
+        self.changed.notify_all();
+        None
+    }
+}
@@ -1,14 +1,33 @@
 
 use lazy_static::lazy_static;
-use regex::Regex;
-use crate::{expr, interpreter::ValueType, bytecode::CodeContext};
+use regex::{Match, Regex};
+use std::collections::HashMap;
+use std::str::FromStr;
 
-static PARSE_REGEX: &'static str = r#"^(?P<keyword>\S+)( (?P<value>\d+$)| ('(?P<name>\S+)')){0,1}$"#;
+use crate::{expr::Expr, instruction::Instruction, interpreter::ValueType, bytecode::CodeContext};
+
+static PARSE_REGEX: &str = r#"^(?P<keyword>\S+)( (?P<value>\d+$)| ('(?P<name>\S+)')){0,1}$"#;
+
+// Builds a host instruction from the value or name operand of its line
+pub type HostParser = fn(Option<ValueType>, Option<&str>) -> Box<dyn Expr>;
+
+// The value operand of keyword, which has to fit in T
+fn number<T: FromStr>(keyword: &str, value: Option<Match>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", keyword))?.as_str();
+    value.parse().map_err(|_| format!("{} {} is out of range", keyword, value))
+}
 
 #[derive(Default)]
-pub struct ParseEngine {}
+pub struct ParseEngine {
+    hosts: HashMap<String, HostParser>,
+}
 impl ParseEngine {
-    pub fn parse<'a>(&self, expr_str: &str, context: &mut CodeContext) -> Box<dyn expr::Expr> {
+    pub fn register(&mut self, keyword: &str, parser: HostParser) {
+        self.hosts.insert(String::from(keyword), parser);
+    }
+
+    // The instruction on one line of source, or what is wrong with the line
+    pub fn parse(&self, expr_str: &str, context: &mut CodeContext) -> Result<Instruction, String> {
 
         let line = context.line();
 
@@ -17,155 +36,320 @@ impl ParseEngine {
         }
         let captures = RE.captures(expr_str);
 
-        let expr: Box<dyn expr::Expr>;
-        match captures {
+        let expr = match captures {
             Some(capture) => {
                 let keyword = capture.name("keyword").unwrap().as_str();
                 let value = capture.name("value");
                 let name = capture.name("name");
+                let no_value = || match value {
+                    Some(_) => Err(format!("{} takes no value", keyword)),
+                    None => Ok(()),
+                };
+                let no_name = || match name {
+                    Some(_) => Err(format!("{} takes no name", keyword)),
+                    None => Ok(()),
+                };
+                let name_of = || name.map(|name| name.as_str())
+                        .ok_or_else(|| format!("{} needs a name", keyword));
 
-                expr = match keyword {
+                match keyword {
+                    "NOOP" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Noop
+                    },
                     "START" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
+                        no_value()?;
+                        no_name()?;
                         context.set_start(line);
-                        Box::new(expr::Start {})
+                        Instruction::Start
                     },
                     "LOAD_VAL" => {
-                        assert!(matches!(name, None));
-                        let value 
-                            = ValueType::from_str_radix(value.unwrap().as_str(), 10)
-                                .unwrap();
-                        Box::new(expr::LoadVal { literal: value })
+                        no_name()?;
+                        let value = number(keyword, value)?;
+                        Instruction::LoadVal(value)
                     },
                     "WRITE_VAR" => {
-                        assert!(matches!(value, None));
-                        let name = name.unwrap().as_str().to_owned();
-                        Box::new(expr::WriteVar { var_name: name })
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        let slot = context.slot(&name);
+                        Instruction::WriteVar(slot)
                     },
                     "READ_VAR" => {
-                        assert!(matches!(value, None));
-                        let name = name.unwrap().as_str().to_owned();
-                        Box::new(expr::ReadVar { var_name: name })
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        let slot = context.slot(&name);
+                        Instruction::ReadVar(slot)
+                    },
+                    "TEE_VAR" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        let slot = context.slot(&name);
+                        Instruction::TeeVar(slot)
+                    },
+                    "POP" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Pop
                     },
                     "ADD" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
-                        Box::new(expr::Add {})
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Add
                     },
                     "MULTIPLY" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
-                        Box::new(expr::Multiply {})
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Multiply
+                    },
+                    "SUBTRACT" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Subtract
+                    },
+                    "EQUAL" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Equal
                     },
                     "LABEL" => {
-                        assert!(matches!(value, None));
-                        let name = name.unwrap().as_str();
+                        no_value()?;
+                        let name = name_of()?;
                         context.set_label(name, line+1);
-                        Box::new(expr::Label {})
+                        Instruction::Label
                     },
                     "CALL" => {
-                        assert!(matches!(value, None));
-                        let name = name.unwrap().as_str().to_owned();
-                        Box::new(expr::Call {
-                            func_name: name,
-                            return_line: line+1
-                        })
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        context.refer(&name);
+                        Instruction::Call { func_line: 0, return_line: line+1 }
                     },
                     "RETURN_VALUE" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
-                        Box::new(expr::ReturnValue {})
+                        no_value()?;
+                        no_name()?;
+                        Instruction::ReturnValue
                     },
                     "RETURN" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
-                        Box::new(expr::Return {})
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Return
                     },
                     "JUMP" => {
-                        assert!(matches!(value, None));
-                        let name = name.unwrap().as_str().to_owned();
-                        Box::new(expr::flow::Jump { label: name })
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        context.refer(&name);
+                        Instruction::Jump(0)
                     },
                     "JUMP_ZERO" => {
-                        assert!(matches!(value, None));
-                        let name = name.unwrap().as_str().to_owned();
-                        Box::new(expr::flow::JumpZero { label: name })
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        context.refer(&name);
+                        Instruction::JumpZero(0)
                     },
                     "LOOP" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
+                        no_value()?;
+                        no_name()?;
 
                         context.push_loop();
-                        Box::new(expr::loops::Loop {
-                            line: line,
-                            loop_var: None,
-                            count: None,
-                            endloop: None,
-                        })
+                        Instruction::Loop {
+                            loop_var: context.slot(&format!("_' i{}", line)),
+                            count_var: context.slot(&format!("_' n{}", line)),
+                            endloop: 0,
+                        }
                     },
                     "ENDLOOP" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
-                        context.consume_loop();
-                        Box::new(expr::loops::EndLoop {
-                            line: line,
-                            loop_var: None,
-                            loopstart: None,
-                        })
+                        no_value()?;
+                        no_name()?;
+                        match context.consume_loop() {
+                            Some(loopstart) => Instruction::EndLoop {
+                                loop_var: context.slot(&format!("_' i{}", loopstart)),
+                                loopstart,
+                            },
+                            // Reported as a link error
+                            None => Instruction::Noop,
+                        }
                     },
                     "WHILE" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
+                        no_value()?;
+                        no_name()?;
                         context.push_while();
-                        Box::new(expr::loops::While {
-                            line: line,
-                            endwhile: None,
-                        })
+                        Instruction::While { endwhile: 0 }
                     },
                     "ENDWHILE" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
-                        context.consume_while();
-                        Box::new(expr::loops::EndWhile {
-                            line: line,
-                            whilestart: None,
-                        })
+                        no_value()?;
+                        no_name()?;
+                        match context.consume_while() {
+                            Some(whilestart) => Instruction::EndWhile { whilestart },
+                            None => Instruction::Noop,
+                        }
                     },
                     "LOAD_ADDR" => {
-                        assert!(matches!(value, None));
-                        let name = name.unwrap().as_str().to_owned();
-                        Box::new(expr::thread::LoadAddr { label: name })
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        context.refer(&name);
+                        Instruction::LoadAddr(0)
                     },
                     "LOAD_CHANNEL" => {
-                        assert!(matches!(name, None));
-                        let value 
-                            = ValueType::from_str_radix(value.unwrap().as_str(), 10)
-                                .unwrap();
-                        Box::new(expr::thread::LoadChannel { channel: value })
+                        no_name()?;
+                        let value = number(keyword, value)?;
+                        Instruction::LoadChannel(value)
                     },
                     "SEND_CHANNEL" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
-                        Box::new(expr::thread::SendChannel {})
+                        no_value()?;
+                        no_name()?;
+                        Instruction::SendChannel
                     },
                     "RECV_CHANNEL" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
-                        Box::new(expr::thread::RecvChannel {})
+                        no_value()?;
+                        no_name()?;
+                        Instruction::RecvChannel
+                    },
+                    "SEND_TO" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::SendTo
+                    },
+                    "RECEIVE" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Receive
                     },
                     "SPAWN" => {
-                        assert!(matches!(value, None));
-                        assert!(matches!(name, None));
-                        Box::new(expr::thread::Spawn {})
+                        no_name()?;
+                        let argc = match value {
+                            Some(_) => number(keyword, value)?,
+                            None => 0,
+                        };
+                        Instruction::Spawn(argc)
                     },
-                    x => panic!("Keyword '{}' is not recognized", x),
-                };
+                    "SPAWN_N" => {
+                        no_name()?;
+                        let count = number(keyword, value)?;
+                        Instruction::SpawnN(count)
+                    },
+                    "JOIN" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Join
+                    },
+                    "SET_PRIORITY" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::SetPriority
+                    },
+                    "YIELD" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Yield
+                    },
+                    "SLEEP" => {
+                        no_name()?;
+                        let ticks = number(keyword, value)?;
+                        Instruction::Sleep(ticks)
+                    },
+                    "NOW" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Now
+                    },
+                    "THREAD_ID" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::ThreadId
+                    },
+                    "THREAD_COUNT" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::ThreadCount
+                    },
+                    "SET_THREAD_NAME" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::SetThreadName(name)
+                    },
+                    "KILL" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Kill
+                    },
+                    "CANCEL" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Cancel
+                    },
+                    "CHECK_CANCEL" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::CheckCancel
+                    },
+                    "MONITOR" => {
+                        no_value()?;
+                        no_name()?;
+                        Instruction::Monitor
+                    },
+                    "READ_GLOBAL" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::ReadGlobal(name)
+                    },
+                    "WRITE_GLOBAL" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::WriteGlobal(name)
+                    },
+                    "ATOMIC_ADD" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::AtomicAdd(name)
+                    },
+                    "COMPARE_AND_SWAP" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::CompareAndSwap(name)
+                    },
+                    "FETCH_AND_SET" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::FetchAndSet(name)
+                    },
+                    "LOCK" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::Lock(name)
+                    },
+                    "UNLOCK" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::Unlock(name)
+                    },
+                    "WAIT" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::Wait(name)
+                    },
+                    "NOTIFY" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::Notify(name)
+                    },
+                    "NOTIFY_ALL" => {
+                        no_value()?;
+                        let name = name_of()?.to_owned();
+                        Instruction::NotifyAll(name)
+                    },
+                    x => match self.hosts.get(x) {
+                        Some(parser) => {
+                            let value = value.map(|_| number(keyword, value)).transpose()?;
+                            Instruction::Host(parser(value, name.map(|n| n.as_str())))
+                        },
+                        None => return Err(format!("keyword {} is not recognized", x)),
+                    },
+                }
             },
-            None => panic!("Line not recognized by the <ParseEngine>"),
-        }
+            None => return Err(format!("'{}' is not an instruction", expr_str)),
+        };
 
-        return expr;
+        Ok(expr)
     }
 }
 
@@ -0,0 +1,219 @@
+use std::fmt::Write;
+
+use crate::bytecode::{ByteCode, LinkError, SourcePos};
+use crate::interpreter::{IThread, Interpreter, RunOutcome, ThreadExit};
+use crate::verify::Violation;
+
+
+pub const HELP: &str = "\
+Instructions run as soon as they are entered, LOOP and WHILE blocks once they
+are closed. A block starting with LABEL defines a function that CALL and
+LOAD_ADDR can use, it ends at its RETURN or RETURN_VALUE and is not run.
+
+:stack          print the stack of the main thread
+:vars           print the variables of the main thread
+:reset          start over with no code and an empty main thread
+:load <file>    run a .br file, its code before START is only defined
+:help           print this help
+:quit           leave the repl
+";
+
+// Runs instructions on a main thread that lives as long as the session.
+// Every input is appended to the code of the session, which is linked and
+// verified as a whole before the main thread runs the new lines
+pub struct Repl {
+    // Source of the inputs that ran, one entry per input
+    inputs: Vec<String>,
+    // Lines of a block that is not closed yet
+    pending: Vec<String>,
+    depth: usize,
+    interpreter: Interpreter,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl {
+            inputs: Vec::new(),
+            pending: Vec::new(),
+            depth: 0,
+            interpreter: Interpreter::new(ByteCode::from_source("")),
+        }
+    }
+
+    // Whether the input so far is an open block
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // Takes one line of input and returns what to print for it
+    pub fn eval_line(&mut self, line: &str) -> String {
+        let line = line.split(';').next().unwrap().trim();
+        if let Some(command) = line.strip_prefix(':') {
+            return self.command(command);
+        }
+        if line.is_empty() {
+            return String::new();
+        }
+
+        let keyword = line.split_whitespace().next().unwrap();
+        let closes = matches!(keyword, "ENDLOOP" | "ENDWHILE");
+        match keyword {
+            "LOOP" | "WHILE" => self.depth += 1,
+            _ if closes => self.depth = self.depth.saturating_sub(1),
+            _ => {},
+        }
+        self.pending.push(String::from(line));
+
+        let defining = self.pending[0].starts_with("LABEL");
+        let done = match defining {
+            true => self.depth == 0 && matches!(keyword, "RETURN" | "RETURN_VALUE"),
+            false => self.depth == 0,
+        };
+        if !done {
+            return String::new();
+        }
+
+        let lines = std::mem::take(&mut self.pending);
+        let label = format!("repl_{}", self.inputs.len());
+        let input = match defining {
+            true => format!("JUMP '{}'\n{}\nLABEL '{}'", label, lines.join("\n"), label),
+            false => lines.iter()
+                    .map(|line| if line == "START" { "NOOP" } else { line })
+                    .collect::<Vec<&str>>()
+                    .join("\n"),
+        };
+        self.execute(input, defining as usize)
+    }
+
+    fn command(&mut self, command: &str) -> String {
+        let (name, arg) = match command.split_once(' ') {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+        match name {
+            "stack" => self.stack(),
+            "vars" => self.variables(),
+            "reset" => {
+                *self = Repl::new();
+                String::new()
+            },
+            "load" => match std::fs::read_to_string(arg) {
+                // Code before START is only defined, like in a program
+                Ok(source) if source.lines().any(|line| line.trim() == "START") => {
+                    let label = format!("repl_{}", self.inputs.len());
+                    let source = source.lines()
+                            .map(|line| match line.trim() {
+                                "START" => format!("LABEL '{}'", label),
+                                _ => String::from(line),
+                            })
+                            .collect::<Vec<String>>()
+                            .join("\n");
+                    self.execute(format!("JUMP '{}'\n{}", label, source), 1)
+                },
+                Ok(source) => self.execute(source, 0),
+                Err(err) => format!("error: {}: {}\n", arg, err),
+            },
+            "help" => String::from(HELP),
+            _ => format!("error: unknown command :{}\n", name),
+        }
+    }
+
+    fn source(&self) -> String {
+        self.inputs.join("\n")
+    }
+
+    // Links the code with input at its end and runs the main thread through
+    // it. An input that does not link or does not reach its end is dropped,
+    // prefix counts the lines put before the input
+    fn execute(&mut self, input: String, prefix: usize) -> String {
+        let source = match self.inputs.is_empty() {
+            true => input.clone(),
+            false => format!("{}\n{}", self.source(), input),
+        };
+        let offset = source.lines().count() - input.lines().count() + prefix;
+
+        let byte_code = match ByteCode::link(&source) {
+            Ok(byte_code) => byte_code,
+            Err(err) => {
+                // Count the lines from the start of the input, a violation in
+                // earlier inputs is one the input ran into
+                let violations = err.violations.into_iter()
+                        .map(|violation| Violation {
+                            pos: SourcePos {
+                                line: violation.pos.line.saturating_sub(offset).max(1),
+                                ..violation.pos
+                            },
+                            ..violation
+                        })
+                        .collect();
+                let err = LinkError { violations, ..err };
+                return format!("error: {}\n", err.to_string().replace('\n', "\nerror: "));
+            },
+        };
+
+        let checkpoint = self.interpreter.checkpoint();
+        let old = self.interpreter.reload(byte_code);
+        match self.run() {
+            Ok(()) => {
+                self.inputs.push(input);
+                self.stack() + &self.variables()
+            },
+            Err(message) => {
+                // Back to the code and the main thread from before the input
+                self.interpreter.reload(old);
+                self.interpreter.restore(checkpoint);
+                format!("error: {}\n", message)
+            },
+        }
+    }
+
+    // Runs the main thread to the end of the code, where it waits for more,
+    // or until it exits
+    fn run(&mut self) -> Result<(), String> {
+        let outcome = self.interpreter.run_until(|kernel| {
+            kernel.threads.get(&kernel.main_thread_id)
+                    .is_none_or(|thread| thread.addr >= kernel.end_addr)
+        });
+
+        let main = self.interpreter.main_thread_id();
+        match (outcome, self.interpreter.thread_exit(main)) {
+            (_, Some(ThreadExit::Trapped(message))) => Err(format!("trapped: {}", message)),
+            (_, Some(_)) => Err(String::from("the main thread exited")),
+            (RunOutcome::Finished, None) => Ok(()),
+            (RunOutcome::Deadlock, None) => Err(String::from("deadlock")),
+            (RunOutcome::StepLimit, None) => Err(String::from("stopped at the step limit")),
+        }
+    }
+
+    fn main_thread(&self) -> Option<&IThread> {
+        self.interpreter.kernel.threads.get(&self.interpreter.main_thread_id())
+    }
+
+    pub fn stack(&self) -> String {
+        match self.main_thread() {
+            Some(thread) => format!("{:?}\n", thread.stack.items),
+            None => String::new(),
+        }
+    }
+
+    // Variables by name, without the counters of LOOP
+    pub fn variables(&self) -> String {
+        let mut text = String::new();
+        if let Some(thread) = self.main_thread() {
+            for (slot, val) in thread.var_table.iter() {
+                match self.interpreter.byte_code.variable_name(slot) {
+                    Some(name) if name.starts_with("_'") => {},
+                    Some(name) => writeln!(text, "{} = {}", name, val).unwrap(),
+                    None => writeln!(text, "slot{} = {}", slot, val).unwrap(),
+                }
+            }
+        }
+        text
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::interpreter::{ThreadIdType, ValueType};
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadInfo {
+    pub id: ThreadIdType,
+    pub priority: ValueType,
+}
+
+pub trait Scheduler: Send {
+    // Pick the next thread to run, runnable is never empty and sorted by id
+    fn next(&mut self, runnable: &[ThreadInfo]) -> ThreadIdType;
+    // Instructions a picked thread may run before the scheduler is asked again
+    fn time_slice(&self) -> usize;
+}
+
+fn next_after(runnable: &[ThreadInfo], last: Option<ThreadIdType>) -> ThreadIdType {
+    let next = last.and_then(|last| runnable.iter().find(|th| th.id > last));
+    next.unwrap_or(&runnable[0]).id
+}
+
+pub struct RoundRobinScheduler {
+    time_slice: usize,
+    last: Option<ThreadIdType>,
+}
+
+impl RoundRobinScheduler {
+    pub fn new(time_slice: usize) -> Self {
+        RoundRobinScheduler {
+            time_slice,
+            last: None,
+        }
+    }
+}
+
+impl Default for RoundRobinScheduler {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn next(&mut self, runnable: &[ThreadInfo]) -> ThreadIdType {
+        let id = next_after(runnable, self.last);
+        self.last = Some(id);
+        id
+    }
+
+    fn time_slice(&self) -> usize {
+        self.time_slice
+    }
+}
+
+pub struct PriorityScheduler {
+    time_slice: usize,
+    last: Option<ThreadIdType>,
+}
+
+impl PriorityScheduler {
+    pub fn new(time_slice: usize) -> Self {
+        PriorityScheduler {
+            time_slice,
+            last: None,
+        }
+    }
+}
+
+impl Scheduler for PriorityScheduler {
+    fn next(&mut self, runnable: &[ThreadInfo]) -> ThreadIdType {
+        // Round robin among the threads with the highest priority
+        let highest = runnable.iter().map(|th| th.priority).max().unwrap();
+        let candidates: Vec<ThreadInfo> = runnable.iter()
+                .filter(|th| th.priority == highest)
+                .copied()
+                .collect();
+        let id = next_after(&candidates, self.last);
+        self.last = Some(id);
+        id
+    }
+
+    fn time_slice(&self) -> usize {
+        self.time_slice
+    }
+}
+
+pub struct RandomScheduler {
+    time_slice: usize,
+    rng: Rng,
+}
+
+impl RandomScheduler {
+    pub fn new(seed: u64, time_slice: usize) -> Self {
+        RandomScheduler {
+            time_slice,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Scheduler for RandomScheduler {
+    fn next(&mut self, runnable: &[ThreadInfo]) -> ThreadIdType {
+        runnable[self.rng.below(runnable.len())].id
+    }
+
+    fn time_slice(&self) -> usize {
+        self.time_slice
+    }
+}
+
+// Probabilistic concurrency testing: random thread priorities with depth - 1
+// points in the first max_steps decisions where the running thread is demoted
+pub struct PctScheduler {
+    rng: Rng,
+    depth: usize,
+    priorities: BTreeMap<ThreadIdType, ValueType>,
+    change_points: Vec<usize>,
+    step: usize,
+}
+
+impl PctScheduler {
+    pub fn new(seed: u64, depth: usize, max_steps: usize) -> Self {
+        let mut rng = Rng::new(seed);
+        let change_points = (1..depth.max(1))
+                .map(|_| rng.below(max_steps.max(1)))
+                .collect();
+        PctScheduler {
+            rng,
+            depth,
+            priorities: BTreeMap::new(),
+            change_points,
+            step: 0,
+        }
+    }
+}
+
+impl Scheduler for PctScheduler {
+    fn next(&mut self, runnable: &[ThreadInfo]) -> ThreadIdType {
+        for th in runnable {
+            if !self.priorities.contains_key(&th.id) {
+                let priority = (self.depth + self.rng.below(1 << 16)) as ValueType;
+                self.priorities.insert(th.id, priority);
+            }
+        }
+
+        let highest = |priorities: &BTreeMap<ThreadIdType, ValueType>| {
+            runnable.iter()
+                .max_by_key(|th| (priorities[&th.id], std::cmp::Reverse(th.id)))
+                .unwrap().id
+        };
+        let mut id = highest(&self.priorities);
+        for (i, point) in self.change_points.iter().enumerate() {
+            if *point == self.step {
+                self.priorities.insert(id, i as ValueType);
+                id = highest(&self.priorities);
+            }
+        }
+        self.step += 1;
+        id
+    }
+
+    fn time_slice(&self) -> usize {
+        1
+    }
+}
+
+// Follows the given choices (indices into runnable) and then always picks the
+// first thread, recording every (choice, runnable count) it made
+pub struct ReplayScheduler {
+    prefix: Vec<usize>,
+    decisions: Arc<Mutex<Vec<(usize, usize)>>>,
+}
+
+impl ReplayScheduler {
+    pub fn new(prefix: Vec<usize>, decisions: Arc<Mutex<Vec<(usize, usize)>>>) -> Self {
+        ReplayScheduler {
+            prefix,
+            decisions,
+        }
+    }
+}
+
+impl Scheduler for ReplayScheduler {
+    fn next(&mut self, runnable: &[ThreadInfo]) -> ThreadIdType {
+        let mut decisions = self.decisions.lock().unwrap();
+        let choice = self.prefix.get(decisions.len())
+                .copied()
+                .unwrap_or(0)
+                .min(runnable.len() - 1);
+        decisions.push((choice, runnable.len()));
+        runnable[choice].id
+    }
+
+    fn time_slice(&self) -> usize {
+        1
+    }
+}
+
+// SplitMix64, so that schedules are reproducible from the seed alone
+#[derive(Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::interpreter::ThreadIdType;
+
+
+// Mutexes and their condition variables, a condition variable is named after
+// the mutex it is used with
+#[derive(Default)]
+pub struct SyncTable {
+    owners: HashMap<String, ThreadIdType>,
+    waiters: HashMap<String, VecDeque<ThreadIdType>>,
+}
+
+impl SyncTable {
+    // Ok(false) when another thread holds the mutex
+    pub fn lock(&mut self, name: &str, thread: ThreadIdType) -> Result<bool, String> {
+        match self.owners.get(name) {
+            None => {
+                self.owners.insert(String::from(name), thread);
+                Ok(true)
+            },
+            Some(owner) if *owner == thread =>
+                Err(format!("Thread {} locked mutex '{}' it already holds", thread, name)),
+            Some(_) => Ok(false),
+        }
+    }
+
+    pub fn unlock(&mut self, name: &str, thread: ThreadIdType) -> Result<(), String> {
+        self.check_owner(name, thread, "unlocked")?;
+        self.owners.remove(name);
+        Ok(())
+    }
+
+    pub fn owner(&self, name: &str) -> Option<ThreadIdType> {
+        self.owners.get(name).copied()
+    }
+
+    // Releases the mutex and queues the thread until it is notified
+    pub fn wait(&mut self, name: &str, thread: ThreadIdType) -> Result<(), String> {
+        self.check_owner(name, thread, "waited on")?;
+        self.owners.remove(name);
+        self.waiters.entry(String::from(name)).or_default().push_back(thread);
+        Ok(())
+    }
+
+    pub fn is_waiting(&self, name: &str, thread: ThreadIdType) -> bool {
+        self.waiters.get(name).is_some_and(|waiters| waiters.contains(&thread))
+    }
+
+    pub fn notify(&mut self, name: &str, thread: ThreadIdType) -> Result<(), String> {
+        self.check_owner(name, thread, "notified")?;
+        if let Some(waiters) = self.waiters.get_mut(name) {
+            waiters.pop_front();
+        }
+        Ok(())
+    }
+
+    pub fn notify_all(&mut self, name: &str, thread: ThreadIdType) -> Result<(), String> {
+        self.check_owner(name, thread, "notified")?;
+        self.waiters.remove(name);
+        Ok(())
+    }
+
+    // Frees the mutexes of a thread that exited and takes it off the
+    // condition variables. Threads blocked on LOCK retry once it exited
+    pub fn release_thread(&mut self, thread: ThreadIdType) {
+        self.owners.retain(|_, owner| *owner != thread);
+        for waiters in self.waiters.values_mut() {
+            waiters.retain(|waiter| *waiter != thread);
+        }
+    }
+
+    fn check_owner(&self, name: &str, thread: ThreadIdType, action: &str) -> Result<(), String> {
+        if self.owner(name) != Some(thread) {
+            return Err(format!("Thread {} {} mutex '{}' it does not hold", thread, action, name));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::bytecode::SourcePos;
+use crate::instruction::Instruction;
+use crate::interpreter::AddrType;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    // Only known to the caller of link, see LinkError::set_file
+    pub file: Option<String>,
+    pub pos: SourcePos,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}: {}", file, self.pos, self.message),
+            None => write!(f, "line {}, column {}: {}", self.pos.line, self.pos.column, self.message),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Value,
+    Addr,
+    Channel,
+    Thread,
+    ReturnAddr,
+    // Not known statically
+    Any,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Value => write!(f, "a Value"),
+            Kind::Addr => write!(f, "an Addr"),
+            Kind::Channel => write!(f, "a Channel"),
+            Kind::Thread => write!(f, "a Thread"),
+            Kind::ReturnAddr => write!(f, "a return address"),
+            Kind::Any => write!(f, "anything"),
+        }
+    }
+}
+
+// The top of the stack, with open set when there may be items below it that
+// are not known
+#[derive(Clone, Debug, PartialEq)]
+struct State {
+    items: Vec<Kind>,
+    open: bool,
+}
+
+impl State {
+    fn closed() -> Self {
+        State { items: Vec::new(), open: false }
+    }
+
+    fn open(items: Vec<Kind>) -> Self {
+        State { items, open: true }
+    }
+
+    // Keeps what both states agree on, counting from the top
+    fn merge(&self, other: &State) -> State {
+        let depth = self.items.len().min(other.items.len());
+        let items = self.items[self.items.len() - depth..].iter()
+                .zip(&other.items[other.items.len() - depth..])
+                .map(|(a, b)| if a == b { *a } else { Kind::Any })
+                .collect();
+        let open = self.open || other.open || self.items.len() != other.items.len();
+        State { items, open }
+    }
+}
+
+struct Verifier<'a> {
+    exprs: &'a [Instruction],
+    states: Vec<Option<State>>,
+    work: Vec<AddrType>,
+    violations: BTreeSet<(AddrType, String)>,
+}
+
+// Checks every path from the start and from the targets of LOAD_ADDR and
+// CALL, returns the violations by address
+pub(crate) fn verify(exprs: &[Instruction], start_addr: AddrType) -> Vec<(AddrType, String)> {
+    let mut verifier = Verifier {
+        exprs,
+        states: vec![None; exprs.len()],
+        work: Vec::new(),
+        violations: BTreeSet::new(),
+    };
+
+    for (addr, expr) in exprs.iter().enumerate() {
+        match expr {
+            // Arguments of a spawned thread sit above its return address
+            Instruction::LoadAddr(target) => verifier.flow(addr, *target, State::open(Vec::new())),
+            Instruction::Call { func_line, .. } =>
+                verifier.flow(addr, *func_line, State::open(vec![Kind::ReturnAddr])),
+            _ => {},
+        }
+    }
+    if !exprs.is_empty() {
+        verifier.flow(start_addr, start_addr, State::closed());
+    }
+
+    while let Some(addr) = verifier.work.pop() {
+        verifier.step(addr);
+    }
+    verifier.violations.into_iter().collect()
+}
+
+impl<'a> Verifier<'a> {
+    fn report(&mut self, addr: AddrType, message: String) {
+        self.violations.insert((addr, message));
+    }
+
+    fn flow(&mut self, from: AddrType, to: AddrType, state: State) {
+        // Running off the end finishes the thread
+        if to == self.exprs.len() {
+            return;
+        }
+        if to > self.exprs.len() {
+            self.report(from, format!("jumps to {} past the end of the program", to));
+            return;
+        }
+        let merged = match self.states[to].clone() {
+            None => state,
+            Some(old) => {
+                if !old.open && !state.open && old.items.len() != state.items.len() {
+                    self.report(to, format!("stack depth is {} on one path and {} on another",
+                        old.items.len(), state.items.len()));
+                }
+                let merged = old.merge(&state);
+                if merged == old {
+                    return;
+                }
+                merged
+            },
+        };
+        self.states[to] = Some(merged);
+        self.work.push(to);
+    }
+
+    fn pop(&mut self, addr: AddrType, state: &mut State, expected: &[Kind]) -> Kind {
+        let name = self.exprs[addr].name();
+        match state.items.pop() {
+            Some(kind) => {
+                let any = kind == Kind::Any || expected.contains(&Kind::Any);
+                if !any && !expected.contains(&kind) {
+                    self.report(addr, format!("{} expects {} but finds {}", name, expected[0], kind));
+                }
+                kind
+            },
+            None if state.open => Kind::Any,
+            None => {
+                self.report(addr, format!("{} pops from an empty stack", name));
+                Kind::Any
+            },
+        }
+    }
+
+    fn step(&mut self, addr: AddrType) {
+        let mut state = self.states[addr].clone().unwrap();
+        let next = addr + 1;
+        const VALUE: &[Kind] = &[Kind::Value];
+
+        match &self.exprs[addr] {
+            Instruction::Noop | Instruction::Start | Instruction::Label
+                | Instruction::Yield | Instruction::Sleep(_) | Instruction::SetThreadName(_)
+                | Instruction::CheckCancel | Instruction::Lock(_) | Instruction::Unlock(_)
+                | Instruction::Wait(_) | Instruction::Notify(_) | Instruction::NotifyAll(_) => {},
+            Instruction::LoadVal(_) | Instruction::ReadVar(_) | Instruction::Receive
+                | Instruction::Now | Instruction::ThreadId | Instruction::ThreadCount
+                | Instruction::ReadGlobal(_) => state.items.push(Kind::Value),
+            Instruction::WriteVar(_) | Instruction::SetPriority | Instruction::WriteGlobal(_) => {
+                self.pop(addr, &mut state, VALUE);
+            },
+            Instruction::Pop => {
+                self.pop(addr, &mut state, &[Kind::Any]);
+            },
+            Instruction::Add | Instruction::Multiply | Instruction::Subtract | Instruction::Equal
+                | Instruction::CompareAndSwap(_) => {
+                self.pop(addr, &mut state, VALUE);
+                self.pop(addr, &mut state, VALUE);
+                state.items.push(Kind::Value);
+            },
+            Instruction::TeeVar(_) | Instruction::AtomicAdd(_) | Instruction::FetchAndSet(_) => {
+                self.pop(addr, &mut state, VALUE);
+                state.items.push(Kind::Value);
+            },
+            Instruction::Call { return_line, .. } => {
+                // The callee may leave a return value
+                self.flow(addr, *return_line, State::open(Vec::new()));
+                return;
+            },
+            Instruction::Return | Instruction::ReturnValue => {
+                if !state.open && !state.items.contains(&Kind::ReturnAddr) {
+                    self.report(addr, format!("{} has no return address on the stack",
+                        self.exprs[addr].name()));
+                }
+                return;
+            },
+            Instruction::Jump(line) => {
+                self.flow(addr, *line, state);
+                return;
+            },
+            Instruction::JumpZero(line) => self.flow(addr, *line, state.clone()),
+            Instruction::Loop { endloop, .. } => {
+                // Only entering the loop takes the count, ENDLOOP goes back
+                // to the line after it
+                self.pop(addr, &mut state, VALUE);
+                self.flow(addr, endloop + 1, state.clone());
+            },
+            Instruction::EndLoop { loopstart, .. } => {
+                self.flow(addr, loopstart + 1, state.clone());
+                match &self.exprs[*loopstart] {
+                    Instruction::Loop { endloop, .. } => self.flow(addr, endloop + 1, state),
+                    _ => self.report(addr, format!("ENDLOOP goes back to {} which is no LOOP",
+                        loopstart)),
+                }
+                return;
+            },
+            Instruction::While { endwhile } => self.flow(addr, endwhile + 1, state.clone()),
+            Instruction::EndWhile { whilestart } => {
+                self.flow(addr, *whilestart, state);
+                return;
+            },
+            Instruction::LoadAddr(_) => state.items.push(Kind::Addr),
+            Instruction::LoadChannel(_) => state.items.push(Kind::Channel),
+            Instruction::SendChannel => {
+                self.pop(addr, &mut state, &[Kind::Channel]);
+                self.pop(addr, &mut state, VALUE);
+            },
+            Instruction::RecvChannel => {
+                self.pop(addr, &mut state, &[Kind::Channel]);
+                state.items.push(Kind::Value);
+            },
+            Instruction::SendTo => {
+                self.pop(addr, &mut state, &[Kind::Thread, Kind::Value]);
+                self.pop(addr, &mut state, VALUE);
+            },
+            Instruction::Spawn(argc) => {
+                self.pop(addr, &mut state, &[Kind::Addr]);
+                for _ in 0..*argc {
+                    self.pop(addr, &mut state, &[Kind::Any]);
+                }
+                state.items.push(Kind::Thread);
+            },
+            Instruction::SpawnN(count) => {
+                for _ in 0..*count {
+                    self.pop(addr, &mut state, &[Kind::Addr]);
+                }
+                for _ in 0..*count {
+                    state.items.push(Kind::Thread);
+                }
+            },
+            Instruction::Join => {
+                self.pop(addr, &mut state, &[Kind::Thread]);
+                state.items.push(Kind::Any);
+            },
+            Instruction::Kill | Instruction::Cancel => {
+                self.pop(addr, &mut state, &[Kind::Thread]);
+            },
+            Instruction::Monitor => {
+                self.pop(addr, &mut state, &[Kind::Channel]);
+                self.pop(addr, &mut state, &[Kind::Thread]);
+            },
+            // Nothing is known about what a host instruction does
+            Instruction::Host(_) => state = State::open(Vec::new()),
+        }
+        self.flow(addr, next, state);
+    }
+}